@@ -1,12 +1,21 @@
 //! Defines the `clap` command line interface for `lark-vm`.
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::{
+    history::History, srcmap::SourceMap, symbols::SymbolTable, trace::TraceFormat, CmdHistory,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The path to the ROM file containing read-only code segment
-    pub romfile: PathBuf,
+    #[arg(required = true)]
+    pub romfile: Option<PathBuf>,
 
     /// Start in debug mode?
     #[arg(short, long)]
@@ -19,12 +28,131 @@ pub struct Cli {
     /// Path to the ROM source file (lark assembly or meadowlark).
     #[arg(short, long)]
     pub src_path: Option<PathBuf>,
+
+    /// Path to a source map relating ROM addresses to source lines. Defaults
+    /// to the ROM file with a `.srcmap` extension, if it exists.
+    #[arg(long)]
+    pub src_map: Option<PathBuf>,
+
+    /// Path to a symbol file mapping labels to addresses. Defaults to the ROM
+    /// file with a `.sym` extension, if it exists.
+    #[arg(long)]
+    pub symbols: Option<PathBuf>,
+
+    /// Number of executed instructions remembered so the debugger can step
+    /// backwards. Zero disables reverse execution.
+    #[arg(long, default_value_t = History::DEFAULT_DEPTH)]
+    pub history_depth: usize,
+
+    /// Resume from a snapshot saved by the debugger's `snapshot save` command
+    /// instead of starting from the beginning of the ROM.
+    #[arg(long, value_name = "SNAPSHOT")]
+    pub resume: Option<PathBuf>,
+
+    /// Run the debugger commands in this file before starting execution.
+    #[arg(long, value_name = "PATH")]
+    pub debug_script: Option<PathBuf>,
+
+    /// File in which commands entered at the `debug>` prompt are remembered
+    /// between sessions. Defaults to `~/.lark_vm_history`.
+    #[arg(long, value_name = "PATH")]
+    pub cmd_history: Option<PathBuf>,
+
+    /// Wait for a GDB remote debugger to connect on the given local TCP port
+    /// instead of using the interactive debugger.
+    #[arg(long, value_name = "PORT")]
+    pub gdb: Option<u16>,
+
+    /// Path to a cost table giving the clock cycles taken by instructions and
+    /// memory accesses, overriding the default costs.
+    #[arg(long, value_name = "PATH")]
+    pub cost_table: Option<PathBuf>,
+
+    /// Count executed instructions and print a hot-spot report on `halt`.
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Also write the profile's call stacks to this file in the folded-stack
+    /// format, for rendering as a flamegraph.
+    #[arg(long, value_name = "PATH", requires = "profile")]
+    pub folded_stacks: Option<PathBuf>,
+
+    /// Write a coverage report to this file on `halt`: an lcov tracefile if
+    /// a source map is loaded, or a listing of the code never executed.
+    #[arg(long, value_name = "PATH")]
+    pub coverage: Option<PathBuf>,
+
+    /// Write a record of every executed instruction to this file.
+    #[arg(long, value_name = "PATH")]
+    pub trace: Option<PathBuf>,
+
+    /// Format of the `--trace` file: `jsonl` or `csv`. Defaults to `csv` if
+    /// the file has a `.csv` extension, and `jsonl` otherwise.
+    #[arg(long, value_name = "FORMAT", requires = "trace")]
+    pub trace_format: Option<TraceFormat>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Speak the Debug Adapter Protocol over stdin/stdout, for use by editors.
+    ///
+    /// The ROM to run is given by the `program` field of the `launch` request.
+    Dap,
+
+    /// Compare two traces written by `--trace` (in either format) and report
+    /// the first place where they diverge.
+    ///
+    /// Exits with status 1 if the traces differ.
+    TraceDiff {
+        a: PathBuf,
+        b: PathBuf,
+
+        /// Path to a symbol file used to name addresses in the report.
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+
+        /// Number of instructions shown before and after the divergence.
+        #[arg(short = 'C', long, default_value_t = 5)]
+        context: usize,
+    },
 }
 
 impl Cli {
+    /// The ROM file to run. Only `None` when a subcommand was given.
+    pub fn rom_path(&self) -> &Path {
+        self.romfile
+            .as_deref()
+            .expect("clap requires a ROM file unless a subcommand is given")
+    }
+
     pub fn rom_src_path(&self) -> PathBuf {
         self.src_path
             .clone()
-            .unwrap_or_else(|| self.romfile.with_extension("").with_extension("lark"))
+            .unwrap_or_else(|| self.rom_path().with_extension("").with_extension("lark"))
+    }
+
+    pub fn src_map_path(&self) -> Option<PathBuf> {
+        self.src_map
+            .clone()
+            .or_else(|| SourceMap::default_path(self.rom_path()))
+    }
+
+    pub fn cmd_history_path(&self) -> Option<PathBuf> {
+        self.cmd_history.clone().or_else(CmdHistory::default_path)
+    }
+
+    pub fn trace_format(&self) -> TraceFormat {
+        self.trace_format.unwrap_or_else(|| {
+            match self.trace.as_ref().and_then(|path| path.extension()) {
+                Some(ext) if ext == "csv" => TraceFormat::Csv,
+                _ => TraceFormat::Jsonl,
+            }
+        })
+    }
+
+    pub fn symbols_path(&self) -> Option<PathBuf> {
+        self.symbols
+            .clone()
+            .or_else(|| SymbolTable::default_path(self.rom_path()))
     }
 }
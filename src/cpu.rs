@@ -1,22 +1,56 @@
 use std::{
-    cell::RefCell,
-    collections::BTreeSet,
+    collections::{BTreeSet, HashMap},
     path::PathBuf,
-    rc::Rc,
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
 };
 
-use self::{dex::DexErr, interrupts::Interrupt, regs::RegisterFile};
+use self::{
+    call_stack::CallStack,
+    coverage::Coverage,
+    dex::DexErr,
+    history::History,
+    intc::{InterruptController, INTC_END, INTC_PENDING},
+    interrupts::Interrupt,
+    profile::Profile,
+    regs::RegisterFile,
+    srcmap::{SourceMap, SrcLoc},
+    symbols::SymbolTable,
+    syscalls::Syscalls,
+    timing::{CostTable, CYCLES_ADDR, CYCLES_END},
+    trace::Tracer,
+    watchpoints::{Access, WatchHit, Watchpoint},
+};
 use crate::utils::s16;
 
+pub use self::{
+    debugger::{CmdHistory, Spr},
+    headless::{CallError, Observer, StepOutcome, CALL_RETURN_ADDR},
+};
+
+pub mod call_stack;
+pub mod coverage;
 mod debugger;
 pub mod decode;
 mod dex;
 mod exn_codes;
+mod headless;
+pub mod history;
 pub mod instr;
+pub mod intc;
 pub mod interrupts;
 pub mod opcodes;
+pub mod profile;
 pub mod regs;
+pub mod snapshot;
+pub mod srcmap;
+pub mod symbols;
+pub mod syscalls;
+pub mod timing;
+pub mod trace;
+pub mod watchpoints;
 
 pub const KIB: usize = 1024;
 pub const STACK_INIT: u16 = Memory::USER_END - 1;
@@ -33,6 +67,7 @@ pub enum ArgStyle {
 pub enum LogMsg {
     /// Once an instruction is decoded, it can be logged with this.
     Instr {
+        pc: u16,
         size: u16,
         name: String,
         args: Vec<(Option<ArgStyle>, String)>,
@@ -55,6 +90,13 @@ pub enum LogMsg {
         addr: u16,
         value: String,
     },
+
+    /// The program raised a breakpoint exception at `pc`. `location` is its
+    /// source line.
+    BreakpointExn {
+        pc: u16,
+        location: String,
+    },
     Error(String),
 }
 
@@ -84,23 +126,88 @@ pub struct Cpu {
     pub lo: s16,
 
     pub mem: Memory,
-
-    pub supervisor: Sender<Signal>,
-    pub pending_interrupts: Receiver<Interrupt>,
+    /// The cycles taken by each instruction.
+    pub cost_table: CostTable,
+
+    /// Receives log messages and signals.
+    pub observer: Box<dyn Observer + Send>,
+    /// Interrupts raised by other threads, if any.
+    pub pending_interrupts: Option<Receiver<Interrupt>>,
+    /// Exception and kernel call handlers registered by the host.
+    pub syscalls: Syscalls,
+    /// The maximum number of steps taken by [`Cpu::call`].
+    pub call_budget: u64,
+    /// A trap (and its cause) raised by the instruction being executed, which
+    /// is taken once it has finished (whether or not interrupts are enabled).
+    pending_trap: Option<(Interrupt, u16)>,
+    /// Where `kret` returns to (`$epc`).
     pub interrupt_return_address: u16,
     pub interrupts_enabled: bool,
+    /// Whether interrupts were enabled before the current one was taken.
+    pub prev_interrupts_enabled: bool,
+    /// Why the current interrupt was taken (`$cause`).
+    pub cause: u16,
+    /// The address involved in the most recent fault (`$badaddr`).
+    pub bad_addr: u16,
 
     pub in_debug_mode: bool,
+    /// When set, pauses are handled by an external debugger (such as the GDB
+    /// stub) instead of the interactive prompt. `step` returns without
+    /// executing anything while `in_debug_mode` is set, and the external
+    /// debugger resumes by calling `execute`.
+    pub external_debugger: bool,
     pub breakpoints: BTreeSet<u16>,
     pub rom_src_path: Option<PathBuf>,
+
+    /// Shadow call stack used by the debugger to produce backtraces.
+    pub call_stack: CallStack,
+
+    pub src_map: Option<SourceMap>,
+    /// When stepping by source line, the line being stepped away from.
+    pub step_from_line: Option<SrcLoc>,
+    pub symbols: SymbolTable,
+
+    /// Undo log used for reverse execution.
+    pub history: History,
+    pub watchpoints: Vec<Watchpoint>,
+    /// The most recent watchpoint hit, if it hasn't been reported yet.
+    pub watch_hit: Option<WatchHit>,
+
+    /// Commands entered at the `debug>` prompt.
+    pub cmd_history: CmdHistory,
+    /// User-defined debugger commands, from `define`.
+    pub dbg_macros: HashMap<String, Vec<String>>,
+    /// Debugger expressions printed every time execution pauses.
+    pub displays: Vec<String>,
+    /// Register values at the current and previous debugger pauses.
+    stop_regs: Option<debugger::RegSnapshot>,
+    prev_stop_regs: Option<debugger::RegSnapshot>,
+    /// What happened during the instruction being executed.
+    step_outcome: StepOutcome,
+
+    /// Writes a record of every executed instruction, if set.
+    pub tracer: Option<Tracer>,
+    /// Counts executed instructions, if profiling.
+    pub profile: Option<Profile>,
+    /// Records which code was executed, if collecting coverage.
+    pub coverage: Option<Coverage>,
 }
 
 impl Cpu {
     pub fn new(
         rom: MemBlock<ROM_SIZE>,
-        vtty_buf: Rc<RefCell<MemBlock<VTTY_BYTES>>>,
+        vtty_buf: Arc<Mutex<MemBlock<VTTY_BYTES>>>,
         logger: Sender<Signal>,
         interrupt_channel: Receiver<Interrupt>,
+    ) -> Self {
+        Self::from_parts(rom, vtty_buf, Box::new(logger), Some(interrupt_channel))
+    }
+
+    fn from_parts(
+        rom: MemBlock<ROM_SIZE>,
+        vtty_buf: Arc<Mutex<MemBlock<VTTY_BYTES>>>,
+        observer: Box<dyn Observer + Send>,
+        pending_interrupts: Option<Receiver<Interrupt>>,
     ) -> Self {
         Self {
             regs: RegisterFile::new(STACK_INIT),
@@ -109,15 +216,44 @@ impl Cpu {
             hi: s16::default(),
             lo: s16::default(),
             mem: Memory::new(rom, vtty_buf),
+            cost_table: CostTable::default(),
 
-            supervisor: logger,
-            pending_interrupts: interrupt_channel,
+            observer,
+            pending_interrupts,
+            syscalls: Syscalls::new(),
+            call_budget: headless::DEFAULT_CALL_BUDGET,
+            pending_trap: None,
             interrupt_return_address: 0x0000,
             interrupts_enabled: true,
+            prev_interrupts_enabled: false,
+            cause: 0,
+            bad_addr: 0,
 
             in_debug_mode: false,
+            external_debugger: false,
             breakpoints: BTreeSet::new(),
             rom_src_path: None,
+
+            call_stack: CallStack::new(),
+
+            src_map: None,
+            step_from_line: None,
+            symbols: SymbolTable::new(),
+
+            history: History::new(History::DEFAULT_DEPTH),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+
+            cmd_history: CmdHistory::default(),
+            dbg_macros: HashMap::new(),
+            displays: Vec::new(),
+            stop_regs: None,
+            prev_stop_regs: None,
+            step_outcome: StepOutcome::Continued,
+
+            tracer: None,
+            profile: None,
+            coverage: None,
         }
     }
 
@@ -128,8 +264,17 @@ impl Cpu {
         self.hi = s16::default();
         self.lo = s16::default();
         self.in_debug_mode = false;
+        self.step_from_line = None;
         self.interrupt_return_address = 0x0000;
         self.interrupts_enabled = true;
+        self.prev_interrupts_enabled = false;
+        self.cause = 0;
+        self.bad_addr = 0;
+        self.call_stack.clear();
+        self.history.clear();
+        self.watch_hit = None;
+        self.mem.mmio.cycles = 0;
+        self.mem.mmio.intc = InterruptController::new();
         self.mem.reset();
     }
 
@@ -152,14 +297,40 @@ impl Cpu {
         self
     }
 
-    pub fn step(&mut self) -> Result<(), DexErr> {
+    pub fn with_src_map(mut self, src_map: Option<SourceMap>) -> Self {
+        self.src_map = src_map;
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn with_history_depth(mut self, depth: usize) -> Self {
+        self.history.set_depth(depth);
+        self
+    }
+
+    pub fn with_cmd_history(mut self, cmd_history: CmdHistory) -> Self {
+        self.cmd_history = cmd_history;
+        self
+    }
+
+    /// Returns the source location of the code at `addr`, if a source map has
+    /// been loaded.
+    pub fn src_loc(&self, addr: u16) -> Option<&SrcLoc> {
+        self.src_map.as_ref()?.lookup(addr)
+    }
+
+    /// Fetches and executes one instruction, after delivering a pending
+    /// interrupt. If the CPU is in debug mode (at a breakpoint, for instance)
+    /// the interactive debugger is started first, unless an external debugger
+    /// is in charge, in which case nothing is executed and
+    /// [`StepOutcome::Breakpoint`] is returned.
+    pub fn step(&mut self) -> StepOutcome {
         // First check for interrupts.
-        if self.interrupts_enabled {
-            // If there are interrupts pending, send ONE (1) to the CPU.
-            if let Ok(interrupt) = self.pending_interrupts.try_recv() {
-                self.send_interrupt(interrupt);
-            }
-        }
+        self.poll_interrupts();
 
         self.fetch();
 
@@ -167,44 +338,143 @@ impl Cpu {
             self.in_debug_mode = true;
         }
 
-        self.decode_and_execute()?;
-        Ok(())
-    }
+        if let Some(from) = &self.step_from_line {
+            if self.src_loc(self.pc).is_some_and(|loc| loc != from) {
+                self.step_from_line = None;
+                self.in_debug_mode = true;
+            }
+        }
 
-    pub fn run(&mut self) {
-        loop {
-            if let Err(e) = self.step() {
-                self.log(LogMsg::Error(format!("{:?}", e)));
+        if self.in_debug_mode {
+            if self.external_debugger {
+                return StepOutcome::Breakpoint;
+            }
+            self.breakpoint();
+            // The debugger may have changed `$pc` or stepped backwards, so
+            // make sure we execute whatever is there now.
+            self.fetch();
+        }
+
+        self.execute_step()
+    }
+
+    /// Executes the instruction in `ir` and reports what happened. An
+    /// instruction which can't be decoded (and which the program doesn't
+    /// handle) is logged as an error.
+    fn execute_step(&mut self) -> StepOutcome {
+        match self.execute() {
+            Ok(()) => self.step_outcome,
+            Err(e) => {
+                self.log(LogMsg::Error(format!(
+                    "illegal instruction at pc=0x{:04X}: {e:?}",
+                    self.pc
+                )));
+                StepOutcome::IllegalInstr
             }
         }
     }
 
-    pub fn signal(&self, sig: Signal) {
-        self.supervisor.send(sig).unwrap();
+    /// Executes the instruction in `ir`, recording it in the history, the
+    /// trace, the profile and the coverage.
+    ///
+    /// If `ir` can't be decoded and the program has an [`Interrupt::ILL_INSTR`]
+    /// handler, the handler is taken with `$epc` and `$badaddr` pointing at
+    /// the instruction. Otherwise the decoding error is returned.
+    pub fn execute(&mut self) -> Result<(), DexErr> {
+        let pc = self.pc;
+        self.step_outcome = StepOutcome::Continued;
+        if let Some(profile) = &mut self.profile {
+            profile.enter(pc, &self.call_stack);
+        }
+        self.begin_step_record();
+        self.begin_trace_record();
+        let mut result = self.decode_and_execute();
+        if result.is_err() && self.interrupt_handler(Interrupt::ILL_INSTR) != 0 {
+            // The program handles undecodable instructions itself.
+            self.illegal_instr();
+            result = Ok(());
+        }
+        if let Some((interrupt, cause)) = self.pending_trap.take() {
+            self.trap(interrupt, cause);
+        }
+        self.end_step_record(result.is_ok());
+        self.end_trace_record(result.is_ok());
+        if let (Some(profile), Ok(())) = (&mut self.profile, &result) {
+            profile.record(pc, self.ir);
+        }
+        if let (Some(coverage), Ok(())) = (&mut self.coverage, &result) {
+            coverage.record(pc, self.ir, self.pc);
+        }
+        result
+    }
+
+    /// Runs until the program stops (see [`StepOutcome`]).
+    pub fn run(&mut self) -> StepOutcome {
+        self.run_until(|_| false)
     }
 
-    pub fn log(&self, msg: LogMsg) {
-        self.signal(Signal::Log(msg));
+    /// Sends a signal to the observer. Halts, breakpoints and illegal
+    /// instructions also become the outcome of the current step.
+    pub fn signal(&mut self, sig: Signal) {
+        match sig {
+            Signal::Halt => self.step_outcome = StepOutcome::Halted,
+            Signal::Breakpoint => self.step_outcome = StepOutcome::Breakpoint,
+            Signal::IllegalInstr => self.step_outcome = StepOutcome::IllegalInstr,
+            Signal::Log(_) => {}
+        }
+        self.observer.signal(sig);
+    }
+
+    pub fn log(&mut self, msg: LogMsg) {
+        self.observer.log(msg);
     }
 
-    fn mem_read_s16(&self, addr_base: u16, addr_offset: i16) -> s16 {
-        self.mem
-            .read_s16(self.mem.compute_offset(addr_base, addr_offset))
+    /// Loads a word on behalf of the executing instruction.
+    fn load_s16(&mut self, addr_base: u16, addr_offset: i16) -> s16 {
+        let addr = self.mem.compute_offset(addr_base, addr_offset);
+        self.check_watchpoints(addr, 2, Access::Read);
+        self.charge_mem_access(addr);
+        let value = self.mem.read_s16(addr);
+        self.trace_mem_access(Access::Read, addr, 2, value.as_u16());
+        value
     }
 
-    fn mem_read_u8(&self, addr_base: u16, addr_offset: i16) -> u8 {
-        self.mem
-            .read_u8(self.mem.compute_offset(addr_base, addr_offset))
+    /// Loads a byte on behalf of the executing instruction.
+    fn load_u8(&mut self, addr_base: u16, addr_offset: i16) -> u8 {
+        let addr = self.mem.compute_offset(addr_base, addr_offset);
+        self.check_watchpoints(addr, 1, Access::Read);
+        self.charge_mem_access(addr);
+        let value = self.mem.read_u8(addr);
+        self.trace_mem_access(Access::Read, addr, 1, value.into());
+        value
     }
 
-    fn mem_write_s16(&mut self, addr_base: u16, addr_offset: i16, value: s16) {
-        self.mem
-            .write_s16(self.mem.compute_offset(addr_base, addr_offset), value);
+    /// Stores a word on behalf of the executing instruction.
+    fn store_s16(&mut self, addr_base: u16, addr_offset: i16, value: s16) {
+        let addr = self.mem.compute_offset(addr_base, addr_offset);
+        self.check_watchpoints(addr, 2, Access::Write);
+        self.record_mem_write(addr, 2);
+        self.charge_mem_access(addr);
+        self.trace_mem_access(Access::Write, addr, 2, value.as_u16());
+        self.mem.write_s16(addr, value);
     }
 
-    fn mem_write_u8(&mut self, addr_base: u16, addr_offset: i16, value: u8) {
-        self.mem
-            .write_u8(self.mem.compute_offset(addr_base, addr_offset), value);
+    /// Stores a byte on behalf of the executing instruction.
+    fn store_u8(&mut self, addr_base: u16, addr_offset: i16, value: u8) {
+        let addr = self.mem.compute_offset(addr_base, addr_offset);
+        self.check_watchpoints(addr, 1, Access::Write);
+        self.record_mem_write(addr, 1);
+        self.charge_mem_access(addr);
+        self.trace_mem_access(Access::Write, addr, 1, value.into());
+        self.mem.write_u8(addr, value);
+    }
+
+    /// Reads `len` bytes starting at `addr` as a string, one `char` per byte.
+    pub fn read_str(&self, addr: u16, len: u16) -> String {
+        (0..len)
+            .map(|i| self.mem.read_u8(addr.wrapping_add(i)))
+            .map(char::from)
+            .collect()
     }
 
     #[allow(clippy::identity_op)]
@@ -243,7 +513,7 @@ impl Memory {
     pub const KERNEL_START: u16 = Self::USER_START + USER_MEM_SIZE as u16;
 
     /// Creates a new memory instance with the given ROM.
-    pub fn new(rom: MemBlock<ROM_SIZE>, vtty_buf: Rc<RefCell<MemBlock<VTTY_BYTES>>>) -> Self {
+    pub fn new(rom: MemBlock<ROM_SIZE>, vtty_buf: Arc<Mutex<MemBlock<VTTY_BYTES>>>) -> Self {
         Self {
             mmio: Mmio::new(vtty_buf),
             rom,
@@ -252,6 +522,14 @@ impl Memory {
         }
     }
 
+    /// Returns true if `addr` is a MMIO device register, where reads and
+    /// writes may have side effects (or be unsupported). The VTTY buffer is
+    /// ordinary memory.
+    pub fn is_device_register(addr: u16) -> bool {
+        (Self::MMIO_START..=Self::MMIO_END).contains(&addr)
+            && !(VTTY_START..=VTTY_END).contains(&addr)
+    }
+
     fn compute_offset(&self, addr_base: u16, addr_offset: i16) -> u16 {
         let addr_base = addr_base as i32;
         let addr_offset = addr_offset as i32;
@@ -314,14 +592,22 @@ pub const VTTY_START: u16 = 128;
 pub const VTTY_END: u16 = VTTY_START + VTTY_BYTES as u16 - 1;
 
 pub struct Mmio {
-    vtty_buf: Rc<RefCell<MemBlock<VTTY_BYTES>>>,
+    /// The VTTY buffer, which may be shared with a display on another thread.
+    vtty_buf: Arc<Mutex<MemBlock<VTTY_BYTES>>>,
+    /// Clock cycles taken so far, readable at [`CYCLES_ADDR`].
+    pub cycles: u64,
+    pub intc: InterruptController,
 }
 
 impl Mmio {
     pub const SIZE: u16 = 2 * KIB as u16;
 
-    pub fn new(vtty_buf: Rc<RefCell<MemBlock<VTTY_BYTES>>>) -> Self {
-        Self { vtty_buf }
+    pub fn new(vtty_buf: Arc<Mutex<MemBlock<VTTY_BYTES>>>) -> Self {
+        Self {
+            vtty_buf,
+            cycles: 0,
+            intc: InterruptController::new(),
+        }
     }
 }
 
@@ -330,10 +616,13 @@ impl MemRw for Mmio {
         match addr {
             VTTY_START..=VTTY_END => {
                 let addr = addr - VTTY_START;
-                let vtty_buf = self.vtty_buf.borrow();
+                let vtty_buf = self.vtty_buf.lock().unwrap();
                 vtty_buf.read_u8(addr)
             }
-            _ => unimplemented!("unimplemented MMIO u8 read from address {}", addr),
+            CYCLES_ADDR..=CYCLES_END => self.cycles.to_be_bytes()[(addr - CYCLES_ADDR) as usize],
+            INTC_PENDING..=INTC_END => self.intc.read(addr).to_be_bytes()[(addr & 1) as usize],
+            // Unmapped device registers read as zero.
+            _ => 0,
         }
     }
 
@@ -342,31 +631,60 @@ impl MemRw for Mmio {
             1 => {} // TODO
             VTTY_START..=VTTY_END => {
                 let addr = addr - VTTY_START;
-                let mut vtty_buf = self.vtty_buf.borrow_mut();
+                let mut vtty_buf = self.vtty_buf.lock().unwrap();
                 vtty_buf.write_u8(addr, value);
             }
-            _ => unimplemented!("unimplemented MMIO u8 write to address {}", addr),
+            // The cycle counter is read-only.
+            CYCLES_ADDR..=CYCLES_END => {}
+            INTC_PENDING..=INTC_END => self.intc.write_u8(addr, value),
+            // Writes to unmapped device registers are ignored.
+            _ => {}
         }
     }
 
     fn read_s16(&self, addr: u16) -> s16 {
-        unimplemented!("unimplemented MMIO s16 read from address {}", addr);
+        match addr {
+            INTC_PENDING..=INTC_END if addr & 1 == 0 => s16::from(self.intc.read(addr)),
+            // Unaligned words, which may straddle two registers.
+            CYCLES_ADDR..=CYCLES_END | INTC_PENDING..=INTC_END => {
+                let hi = self.read_u8(addr) as u16;
+                let lo = self.read_u8(addr + 1) as u16;
+                s16::from((hi << 8) | lo)
+            }
+            // The VTTY buffer holds words low byte first (see `write_s16`).
+            VTTY_START..=VTTY_END => {
+                let lo = self.read_u8(addr) as u16;
+                let hi = self.read_u8(addr + 1) as u16;
+                s16::from((hi << 8) | lo)
+            }
+            // Unmapped device registers read as zero.
+            _ => s16::from(0u16),
+        }
     }
 
     #[allow(clippy::identity_op)]
     fn write_s16(&mut self, addr: u16, value: s16) {
         match addr {
             1 => {} // TODO
+            // The cycle counter is read-only.
+            CYCLES_ADDR..=CYCLES_END => {}
+            INTC_PENDING..=INTC_END if addr & 1 == 0 => self.intc.write(addr, value.as_u16()),
+            INTC_PENDING..=INTC_END => {
+                let [hi, lo] = value.as_u16().to_be_bytes();
+                self.write_u8(addr, hi);
+                self.write_u8(addr + 1, lo);
+            }
             VTTY_START..=VTTY_END => {
                 let addr = addr - VTTY_START;
                 let value = value.as_u16();
                 let value_lo = (value & 0x00FF) as u8;
                 let value_hi = (value >> 8) as u8;
-                let mut vtty_buf = self.vtty_buf.borrow_mut();
+                let mut vtty_buf = self.vtty_buf.lock().unwrap();
                 vtty_buf.write_u8(addr + 0, value_lo);
                 vtty_buf.write_u8(addr + 1, value_hi);
             }
-            _ => unimplemented!("unimplemented MMIO s16 write to address {}", addr),
+            // Writes to unmapped device registers are ignored.
+            _ => {}
         }
     }
 }
@@ -434,3 +752,20 @@ impl<const N: usize> MemRw for MemBlock<N> {
         self.mem[addr as usize + 0] = hi;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vtty_words() {
+        let mut cpu = Cpu::headless(MemBlock::from_vec(vec![0x04]).unwrap());
+        cpu.mem.write_s16(VTTY_START + 4, 0x4142u16.into());
+        assert_eq!(cpu.mem.read_s16(VTTY_START + 4).as_u16(), 0x4142);
+        assert_eq!(cpu.mem.read_u8(VTTY_START + 4), 0x42);
+        assert_eq!(cpu.mem.read_u8(VTTY_START + 5), 0x41);
+
+        cpu.mem.write_u8(VTTY_END, 0x7A);
+        assert_eq!(cpu.mem.read_s16(VTTY_END).as_u16(), 0x007A);
+    }
+}
@@ -0,0 +1,142 @@
+//! A shadow call stack which the CPU maintains as it executes `jal`/`jral`
+//! (calls which link into `$ra`) and `jr $ra` (returns).
+//!
+//! The shadow stack is only used for debugging, so it doesn't need to be
+//! exact. If a program returns somewhere other than where it was called from
+//! the stack just gets truncated to the best match. A program which calls
+//! without ever returning would grow it forever, so only the most recent
+//! [`MAX_DEPTH`] frames are kept.
+
+use std::collections::VecDeque;
+
+/// The most frames kept. Older ones are dropped to make room for new calls.
+pub const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame {
+    /// Address of the call instruction.
+    pub call_site: u16,
+    /// Address the callee will return to.
+    pub return_addr: u16,
+    /// Address of the first instruction of the callee.
+    pub callee: u16,
+    /// The value of `$sp` at the time of the call.
+    pub sp: u16,
+}
+
+/// A change made to a [`CallStack`] by one instruction, which can be undone.
+#[derive(Debug, Clone)]
+pub enum CallStackChange {
+    /// A frame was pushed, dropping the oldest frame if the stack was full.
+    Pushed { evicted: Option<CallFrame> },
+    /// A return popped these frames, oldest first.
+    Returned { popped: Vec<CallFrame> },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    /// The oldest call is first, the most recent call is last.
+    frames: VecDeque<CallFrame>,
+    /// Number of older frames dropped to stay within [`MAX_DEPTH`].
+    evicted: usize,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, frame: CallFrame) -> CallStackChange {
+        let evicted = match self.frames.len() == MAX_DEPTH {
+            true => self.frames.pop_front(),
+            false => None,
+        };
+        self.evicted += evicted.is_some() as usize;
+        self.frames.push_back(frame);
+        CallStackChange::Pushed { evicted }
+    }
+
+    /// Records a return to `target`. Pops every frame down to and including
+    /// the most recent one which would return to `target`. Returns to an
+    /// address that no frame expects are ignored, so `None` is returned.
+    pub fn ret(&mut self, target: u16) -> Option<CallStackChange> {
+        let i = self.frames.iter().rposition(|f| f.return_addr == target)?;
+        let popped = self.frames.split_off(i).into();
+        Some(CallStackChange::Returned { popped })
+    }
+
+    /// Undoes `change`, which must be the most recent change made.
+    pub fn undo(&mut self, change: &CallStackChange) {
+        match change {
+            CallStackChange::Pushed { evicted } => {
+                self.frames.pop_back();
+                if let Some(frame) = evicted {
+                    self.frames.push_front(*frame);
+                    self.evicted -= 1;
+                }
+            }
+            CallStackChange::Returned { popped } => self.frames.extend(popped),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.evicted = 0;
+    }
+
+    /// The recorded frames, oldest call first.
+    pub fn frames(&self) -> &VecDeque<CallFrame> {
+        &self.frames
+    }
+
+    /// Whether older frames were dropped, so the oldest recorded frame isn't
+    /// the outermost one.
+    pub fn is_truncated(&self) -> bool {
+        self.evicted > 0
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_is_capped() {
+        let mut stack = CallStack::new();
+        for i in 0..MAX_DEPTH as u16 + 10 {
+            stack.push(CallFrame {
+                call_site: i,
+                return_addr: i + 3,
+                callee: 0x0900,
+                sp: 0,
+            });
+        }
+        assert_eq!(stack.depth(), MAX_DEPTH);
+        assert_eq!(stack.frames()[0].call_site, 10);
+        assert!(stack.is_truncated());
+
+        let change = stack.ret(MAX_DEPTH as u16 + 3).unwrap();
+        assert_eq!(stack.depth(), MAX_DEPTH - 10);
+        stack.undo(&change);
+        assert_eq!(stack.depth(), MAX_DEPTH);
+        assert_eq!(
+            stack.frames()[MAX_DEPTH - 1].call_site,
+            MAX_DEPTH as u16 + 9
+        );
+
+        // Undoing the pushes which evicted frames brings them back.
+        let change = stack.push(CallFrame {
+            call_site: 0x0800,
+            return_addr: 0x0803,
+            callee: 0x0900,
+            sp: 0,
+        });
+        stack.undo(&change);
+        assert_eq!(stack.frames()[0].call_site, 10);
+        assert!(stack.is_truncated());
+    }
+}
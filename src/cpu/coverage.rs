@@ -0,0 +1,299 @@
+//! Code coverage, enabled with `--coverage`.
+//!
+//! Records which instructions were executed and which way each conditional
+//! branch (`bt`/`bf`) went. When a source map is loaded, the report is written
+//! in the [lcov] tracefile format, with line and branch counts for the source
+//! lines each instruction came from. Otherwise it lists the ROM addresses which
+//! were never executed and the branches which only went one way.
+//!
+//! Without a source map, the instructions in the ROM are found by decoding it
+//! from the start, so data embedded in the ROM may show up as instructions.
+//!
+//! [lcov]: https://github.com/linux-test-project/lcov
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use bitvec::prelude::*;
+
+use super::{
+    instr::{ops::OpcodeRegImm, Instr},
+    Cpu, MemRw, Memory,
+};
+
+#[derive(Default)]
+pub struct Coverage {
+    /// Execution counts by address.
+    pub hits: BTreeMap<u16, u64>,
+    /// How often each conditional branch was taken and not taken.
+    pub branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an execution of the instruction `ir` at `pc`, after which
+    /// execution continued at `next_pc`.
+    pub fn record(&mut self, pc: u16, ir: u32, next_pc: u16) {
+        *self.hits.entry(pc).or_default() += 1;
+        if let Some(instr) = decode(ir).filter(is_branch) {
+            let counts = self.branches.entry(pc).or_default();
+            if next_pc == pc.wrapping_add(instr.instr_size()) {
+                counts.1 += 1;
+            } else {
+                counts.0 += 1;
+            }
+        }
+    }
+}
+
+fn decode(ir: u32) -> Option<Instr> {
+    Instr::from_bits(ir.view_bits::<Msb0>()).ok()
+}
+
+fn is_branch(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::RI {
+            opcode: OpcodeRegImm::BT | OpcodeRegImm::BF,
+            ..
+        }
+    )
+}
+
+/// Line and branch counts for one source file.
+#[derive(Default)]
+struct FileCoverage {
+    /// The most times any instruction from each line was executed.
+    lines: BTreeMap<u32, u64>,
+    /// Branch counts by line and branch address, or `None` if the branch never
+    /// executed.
+    branches: BTreeMap<(u32, u16), Option<(u64, u64)>>,
+}
+
+impl Cpu {
+    pub fn with_coverage(mut self, coverage: bool) -> Self {
+        self.coverage = coverage.then(Coverage::new);
+        self
+    }
+
+    /// Decodes the instruction at `addr`.
+    fn instr_at(&self, addr: u16) -> Option<Instr> {
+        let hi = self.mem.read_s16(addr).as_u16() as u32;
+        let lo = self.mem.read_s16(addr.wrapping_add(2)).as_u16() as u32;
+        decode((hi << 16) | lo)
+    }
+
+    /// Decodes the instructions from `start` up to (but excluding) `end`,
+    /// stopping early at anything which isn't an instruction.
+    fn instrs_in(&self, start: u16, end: u16) -> Vec<(u16, Instr)> {
+        let mut instrs = Vec::new();
+        let mut addr = start;
+        while addr < end {
+            let Some(instr) = self.instr_at(addr) else {
+                break;
+            };
+            instrs.push((addr, instr));
+            addr += instr.instr_size();
+        }
+        instrs
+    }
+
+    /// Writes the coverage report, if collecting coverage: an lcov tracefile
+    /// if a source map is loaded, or a listing of uncovered code otherwise.
+    pub fn write_coverage(&self, path: &Path) -> Result<(), String> {
+        let Some(coverage) = &self.coverage else {
+            return Ok(());
+        };
+        let report = match &self.src_map {
+            Some(_) => self.lcov_report(coverage),
+            None => self.uncovered_report(coverage),
+        };
+        std::fs::write(path, report)
+            .map_err(|e| format!("could not write coverage report `{}`: {e}", path.display()))
+    }
+
+    fn lcov_report(&self, coverage: &Coverage) -> String {
+        let mut files: HashMap<&PathBuf, FileCoverage> = HashMap::new();
+        for (start, end, loc) in self.src_map.iter().flat_map(|map| map.ranges()) {
+            let file = files.entry(&loc.file).or_default();
+            let line_hits = file.lines.entry(loc.line).or_default();
+            for (addr, instr) in self.instrs_in(start, end) {
+                let hits = coverage.hits.get(&addr).copied().unwrap_or(0);
+                *line_hits = (*line_hits).max(hits);
+                if is_branch(&instr) {
+                    file.branches
+                        .insert((loc.line, addr), coverage.branches.get(&addr).copied());
+                }
+            }
+        }
+
+        let mut files: Vec<_> = files.into_iter().collect();
+        files.sort_by_key(|(path, _)| *path);
+
+        let mut out = String::new();
+        for (path, file) in files {
+            writeln!(out, "TN:").unwrap();
+            writeln!(out, "SF:{}", path.display()).unwrap();
+
+            // Each branch instruction is a block of two branches: taken and
+            // not taken.
+            let (mut found, mut hit) = (0, 0);
+            let mut prev_line = None;
+            let mut block = 0;
+            for (&(line, _addr), &counts) in &file.branches {
+                block = if prev_line == Some(line) {
+                    block + 1
+                } else {
+                    0
+                };
+                prev_line = Some(line);
+                for (branch, count) in [(0, counts.map(|c| c.0)), (1, counts.map(|c| c.1))] {
+                    let taken = match count {
+                        Some(count) => count.to_string(),
+                        None => "-".to_string(),
+                    };
+                    writeln!(out, "BRDA:{line},{block},{branch},{taken}").unwrap();
+                    found += 1;
+                    hit += count.is_some_and(|c| c > 0) as u32;
+                }
+            }
+            if found > 0 {
+                writeln!(out, "BRF:{found}").unwrap();
+                writeln!(out, "BRH:{hit}").unwrap();
+            }
+
+            for (line, hits) in &file.lines {
+                writeln!(out, "DA:{line},{hits}").unwrap();
+            }
+            writeln!(out, "LF:{}", file.lines.len()).unwrap();
+            writeln!(
+                out,
+                "LH:{}",
+                file.lines.values().filter(|&&h| h > 0).count()
+            )
+            .unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    fn uncovered_report(&self, coverage: &Coverage) -> String {
+        let rom = &self.mem.rom.mem[..];
+        let rom_len = rom.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        let instrs = self.instrs_in(Memory::ROM_START, Memory::ROM_START + rom_len as u16);
+
+        let fmt_instr = |addr: u16, instr: &Instr| {
+            format!(
+                "{:<20} {}",
+                self.symbols.fmt_addr(addr),
+                instr
+                    .display_at(addr, &self.symbols)
+                    .to_string()
+                    .replace('\t', " ")
+            )
+        };
+
+        let mut out = String::new();
+        let covered = instrs
+            .iter()
+            .filter(|(addr, _)| coverage.hits.contains_key(addr))
+            .count();
+        writeln!(
+            out,
+            "{covered} of {} instructions executed ({:.2}%)",
+            instrs.len(),
+            100.0 * covered as f64 / instrs.len().max(1) as f64
+        )
+        .unwrap();
+
+        writeln!(out, "\nNever executed:").unwrap();
+        for (addr, instr) in &instrs {
+            if !coverage.hits.contains_key(addr) {
+                writeln!(out, "  {}", fmt_instr(*addr, instr)).unwrap();
+            }
+        }
+
+        writeln!(out, "\nBranches which only went one way:").unwrap();
+        for (addr, instr) in instrs.iter().filter(|(_, instr)| is_branch(instr)) {
+            match coverage.branches.get(addr) {
+                Some((0, _)) => writeln!(out, "  {}  (never taken)", fmt_instr(*addr, instr)),
+                Some((_, 0)) => writeln!(out, "  {}  (always taken)", fmt_instr(*addr, instr)),
+                _ => Ok(()),
+            }
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{srcmap::SourceMap, MemBlock};
+
+    /// `li $t0, 1`, `bt $t0, 8`, `bf $t0, 8`, `nop` and `halt`.
+    const ROM: &[u8] = &[
+        0x42, 0x40, 0x00, 0x40, 0x32, 0x40, 0x02, 0x00, 0x3E, 0x40, 0x02, 0x00, 0x08, 0x04,
+    ];
+    const LI: u32 = 0x4240_0040;
+    const BT: u32 = 0x3240_0200;
+    const BF: u32 = 0x3E40_0200;
+
+    #[test]
+    fn record_branches() {
+        let mut coverage = Coverage::new();
+        coverage.record(0x0800, LI, 0x0804);
+        coverage.record(0x0804, BT, 0x080C);
+        coverage.record(0x0804, BT, 0x080C);
+        coverage.record(0x0804, BT, 0x0808);
+        coverage.record(0x0808, BF, 0x080C);
+
+        assert_eq!(coverage.hits[&0x0804], 3);
+        assert_eq!(coverage.branches.get(&0x0800), None);
+        assert_eq!(coverage.branches[&0x0804], (2, 1));
+        assert_eq!(coverage.branches[&0x0808], (0, 1));
+    }
+
+    #[test]
+    fn lcov() {
+        let src_map = SourceMap::parse(
+            "0x0800 0x0804 main.s:1\n\
+             0x0804 0x080C main.s:2\n\
+             0x080C 0x080E main.s:4\n",
+            Path::new(""),
+        )
+        .unwrap();
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let cpu = Cpu::headless(rom).with_src_map(Some(src_map));
+
+        // The `bf` and the last line never run.
+        let mut coverage = Coverage::new();
+        coverage.record(0x0800, LI, 0x0804);
+        coverage.record(0x0804, BT, 0x080C);
+
+        assert_eq!(
+            cpu.lcov_report(&coverage),
+            "TN:\n\
+             SF:main.s\n\
+             BRDA:2,0,0,1\n\
+             BRDA:2,0,1,0\n\
+             BRDA:2,1,0,-\n\
+             BRDA:2,1,1,-\n\
+             BRF:4\n\
+             BRH:1\n\
+             DA:1,1\n\
+             DA:2,1\n\
+             DA:4,0\n\
+             LF:3\n\
+             LH:2\n\
+             end_of_record\n"
+        );
+    }
+}
@@ -1,34 +1,50 @@
-use std::str::FromStr;
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use bitvec::prelude::*;
 
 use crate::utils::s16;
 
-use super::{regs::Reg, Cpu};
+use super::{
+    instr::Instr,
+    regs::{Reg, RegisterFile},
+    watchpoints::{WatchKind, Watchpoint},
+    Cpu, MemRw, Memory, STACK_INIT,
+};
+
+pub use self::script::CmdHistory;
+use self::script::DbgInput;
+
+mod script;
 
 impl Cpu {
     /// Pauses execution until user presses enter.
     /// Allow the user to enter commands to query the state of the CPU.
     pub fn breakpoint(&mut self) {
-        use std::io::{self, BufRead, Write};
-
         if !self.in_debug_mode {
             return;
         }
 
-        let stdin = io::stdin();
-        let mut stdin = stdin.lock();
-        let mut line = String::new();
+        if let Some(hit) = self.watch_hit.take() {
+            eprintln!(
+                "{} watchpoint hit: 0x{:04X} accessed by instruction at {}",
+                hit.watchpoint.kind,
+                hit.addr,
+                self.symbols.fmt_addr(hit.pc),
+            );
+        }
+        self.print_location();
 
-        loop {
-            eprint!("debug> ");
+        self.save_stop_regs();
+        self.print_displays();
 
-            io::stdout().flush().unwrap();
-            stdin.read_line(&mut line).unwrap();
+        let mut input = DbgInput::interactive(&mut self.cmd_history);
 
-            if line.trim().is_empty() {
+        while let Some(line) = self.read_prompt_line(&mut input) {
+            if line.is_empty() {
                 break;
             }
 
-            if matches!(line.trim(), "help" | "h" | "?" | "--help" | "-h") {
+            if matches!(line.as_str(), "help" | "h" | "?" | "--help" | "-h") {
                 eprintln!();
                 eprintln!("HELP");
                 eprintln!("--------------------------------------------------------------");
@@ -41,65 +57,120 @@ impl Cpu {
                 eprintln!("breakpoints | b         Print a list of all current breakpoints");
                 eprintln!("+b <RVAL>               Add a breakpoint at the given");
                 eprintln!("+breakpoint <RVAL>      instruction address");
+                eprintln!("+b <FILE>:<UINT>        Add a breakpoint at the given source");
+                eprintln!("break <FILE>:<UINT>     line (requires a source map)");
                 eprintln!("-b #<UINT>              Remove the n-th breakpoint");
                 eprintln!("-breakpoint #<UINT>");
                 eprintln!("-b <RVAL>               Remove breakpoint at given address");
                 eprintln!("-breakpoint <RVAL>");
                 eprintln!("continue | c            Continue execution, ignoring breakpoints");
+                eprintln!("next | n                Continue until the next source line");
+                eprintln!("list | l                Print the source around the current line");
+                eprintln!("list <UINT>             ... with the given number of lines of context");
+                eprintln!("x/<UINT><FMT> <RVAL>    Examine memory starting at an address.");
+                eprintln!("                        FMT is one of `b` (hex bytes), `w` (hex");
+                eprintln!("                        words), `d` (signed words), `c` (ASCII)");
+                eprintln!("str <RVAL> <RVAL>       Print a string given its address and length");
+                eprintln!("fill <RVAL> <RVAL> <RVAL>");
+                eprintln!("                        Fill memory: `fill ADDR LEN BYTE`");
+                eprintln!("load <PATH> <RVAL>      Copy a file's contents into memory at an");
+                eprintln!("                        address");
+                eprintln!("snapshot save <PATH>    Save a snapshot of the machine to a file");
+                eprintln!("snapshot restore <PATH> Restore the machine from a snapshot file");
+                eprintln!("reverse-step | rs       Undo the last instruction");
+                eprintln!("reverse-step <UINT>     Undo the last n instructions");
+                eprintln!("reverse-continue | rc   Undo instructions until a breakpoint or");
+                eprintln!("                        watchpoint is reached");
+                eprintln!("history-depth           Print how many instructions can be undone");
+                eprintln!("history-depth <UINT>    Set the maximum number of instructions");
+                eprintln!("                        remembered for reverse execution");
+                eprintln!("watchpoints | w         Print a list of all current watchpoints");
+                eprintln!("watch <RVAL>            Pause when a word at the address is written");
+                eprintln!("rwatch <RVAL>           ... is read");
+                eprintln!("awatch <RVAL>           ... is read or written");
+                eprintln!("-w #<UINT>              Remove the n-th watchpoint");
+                eprintln!("backtrace | bt          Print the call stack");
+                eprintln!("frame <UINT>            Print the stack slice of the n-th frame");
+                eprintln!("                        of the backtrace");
+                eprintln!("display <RVAL>          Print a value every time execution pauses");
+                eprintln!("display                 Print all displayed values");
+                eprintln!("undisplay <UINT>        Stop displaying the n-th value");
+                eprintln!("source <PATH>           Run the debugger commands in a file");
+                eprintln!("define <NAME>           Define a command from the lines which");
+                eprintln!("                        follow, up to `end`");
+                eprintln!("history                 Print previously entered commands");
+                eprintln!("!!                      Repeat the previous command");
+                eprintln!("!<UINT>                 Repeat the n-th command in the history");
                 eprintln!("--------------------------------------------------------------");
                 continue;
             }
 
-            let cmd = DbgCmd::parse(&mut &line[..]).unwrap_or_else(|err| {
-                eprintln!("error: {}", err);
-                DbgCmd::Eval(DbgVal::U16(0))
-            });
-
-            self.eval_dbg_cmd(&cmd);
-
-            if let DbgCmd::Continue = cmd {
-                break;
+            match self.run_dbg_line(&line, &mut input, 0) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(err) => eprintln!("error: {err}"),
             }
-
-            line.clear();
         }
+
+        input.finish(&mut self.cmd_history);
     }
 
-    fn eval_dbg_cmd(&mut self, cmd: &DbgCmd) {
+    fn eval_dbg_cmd(&mut self, cmd: &DbgCmd) -> Result<(), String> {
         match cmd {
             DbgCmd::Eval(val) => eprintln!(
                 "-> {val}, 0x{val:0x}, 0b{val:0b}",
-                val = self.eval_dbg_val_rvalue(val)
+                val = self.eval_dbg_val_rvalue(val)?
             ),
             DbgCmd::Set(lhs, rhs) => {
-                let rhs = self.eval_dbg_val_rvalue(rhs);
-                let old = self.set_lvalue(lhs, rhs);
+                let rhs = self.eval_dbg_val_rvalue(rhs)?;
+                let old = self.set_lvalue(lhs, rhs)?;
                 eprintln!("{old} -> {rhs}",)
             }
             DbgCmd::PrintStack { depth } => self.print_stack(*depth),
             DbgCmd::ListBreakpoints => {
                 eprintln!("breakpoints:");
                 for (i, bp) in self.breakpoints.iter().enumerate() {
-                    eprintln!("\t #{}: 0x{:04X} = {}", i + 1, bp, bp);
+                    match self.symbols.describe(*bp) {
+                        Some(sym) => eprintln!("\t #{}: 0x{:04X} = {} <{}>", i + 1, bp, bp, sym),
+                        None => eprintln!("\t #{}: 0x{:04X} = {}", i + 1, bp, bp),
+                    }
                 }
                 if self.breakpoints.is_empty() {
                     eprintln!("\t<no breakpoints set>");
                 }
             }
             DbgCmd::AddBreakpoint(val) => {
-                let address = self.eval_dbg_val_rvalue(val);
+                let address = self.eval_dbg_val_rvalue(val)?;
                 self.breakpoints.insert(address);
                 eprintln!("added breakpoint at 0x{:04X} = {}", address, address);
             }
+            DbgCmd::AddSrcBreakpoint { file, line } => {
+                let Some(src_map) = &self.src_map else {
+                    return Err("no source map loaded".to_string());
+                };
+                let Some((address, loc)) = src_map.resolve_line(file, *line) else {
+                    return Err(format!("no code at or after {}:{}", file.display(), line));
+                };
+                eprintln!(
+                    "added breakpoint at 0x{:04X} = {} ({})",
+                    address, address, loc
+                );
+                self.breakpoints.insert(address);
+            }
             DbgCmd::RemoveBreakpoint(val) => {
-                let Some(index) = self.eval_dbg_val_rvalue(val).checked_sub(1) else {
-                    eprintln!(
-                        "Invalid breakpoint ordinal. Enter a value between 1 and {}.",
+                let ordinal = self.eval_dbg_val_rvalue(val)?;
+                let invalid_ordinal = || {
+                    format!(
+                        "invalid breakpoint ordinal. Enter a value between 1 and {}.",
                         self.breakpoints.len()
-                    );
-                    return;
+                    )
+                };
+                let Some(index) = ordinal.checked_sub(1) else {
+                    return Err(invalid_ordinal());
+                };
+                let Some(&address) = self.breakpoints.iter().nth(index as usize) else {
+                    return Err(invalid_ordinal());
                 };
-                let address = *self.breakpoints.iter().nth(index as usize).unwrap();
                 self.breakpoints.remove(&address);
                 eprintln!(
                     "removed breakpoint #{}: 0x{:04X} = {}",
@@ -112,108 +183,802 @@ impl Cpu {
                 self.in_debug_mode = false;
                 eprintln!("continuing execution...");
             }
+            DbgCmd::NextLine => match self.src_loc(self.pc) {
+                Some(loc) => {
+                    self.step_from_line = Some(loc.clone());
+                    self.in_debug_mode = false;
+                }
+                None => eprintln!("no source line for the current instruction, stepping once"),
+            },
+            DbgCmd::List { context } => self.print_src_context(*context),
+            DbgCmd::Examine { count, fmt, addr } => {
+                let addr = self.eval_dbg_val_rvalue(addr)?;
+                self.print_examine(addr, *count, *fmt);
+            }
+            DbgCmd::PrintStr { addr, len } => {
+                let addr = self.eval_dbg_val_rvalue(addr)?;
+                let len = self.eval_dbg_val_rvalue(len)?;
+                check_debug_access(addr, len as usize)?;
+                eprintln!("0x{addr:04X}: {:?}", self.read_str(addr, len));
+            }
+            DbgCmd::Fill { addr, len, byte } => {
+                let addr = self.eval_dbg_val_rvalue(addr)?;
+                let len = self.eval_dbg_val_rvalue(len)?;
+                let byte = self.eval_dbg_val_rvalue(byte)? as u8;
+                check_debug_access(addr, len as usize)?;
+                for i in 0..len {
+                    self.mem.write_u8(addr + i, byte);
+                }
+                eprintln!("filled {len} bytes at 0x{addr:04X} with 0x{byte:02X}");
+            }
+            DbgCmd::SaveSnapshot(path) => {
+                self.save_snapshot(path)?;
+                eprintln!("saved snapshot to `{}`", path.display());
+            }
+            DbgCmd::RestoreSnapshot(path) => {
+                self.load_snapshot(path)?;
+                self.fetch();
+                self.print_location();
+            }
+            DbgCmd::Load { path, addr } => {
+                let addr = self.eval_dbg_val_rvalue(addr)?;
+                let bytes = std::fs::read(path)
+                    .map_err(|err| format!("could not read `{}`: {err}", path.display()))?;
+                check_debug_access(addr, bytes.len())?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    self.mem.write_u8(addr + i as u16, *byte);
+                }
+                eprintln!(
+                    "loaded {} bytes from `{}` at 0x{addr:04X}",
+                    bytes.len(),
+                    path.display()
+                );
+            }
+            DbgCmd::ReverseStep(count) => {
+                for i in 0..*count {
+                    if self.reverse_step().is_none() {
+                        eprintln!("reached the beginning of the history after {i} steps");
+                        break;
+                    }
+                }
+                self.print_location();
+            }
+            DbgCmd::ReverseContinue => {
+                let mut steps = 0;
+                loop {
+                    let Some(record) = self.reverse_step() else {
+                        eprintln!("reached the beginning of the history after {steps} steps");
+                        break;
+                    };
+                    steps += 1;
+                    if record.watch_hit {
+                        eprintln!("stopped at a watchpoint hit after {steps} steps");
+                        break;
+                    }
+                    if self.breakpoints.contains(&self.pc) {
+                        eprintln!("stopped at a breakpoint after {steps} steps");
+                        break;
+                    }
+                }
+                self.print_location();
+            }
+            DbgCmd::HistoryDepth(None) => eprintln!(
+                "{} of at most {} instructions can be undone",
+                self.history.len(),
+                self.history.depth()
+            ),
+            DbgCmd::HistoryDepth(Some(depth)) => {
+                self.history.set_depth(*depth);
+                eprintln!("remembering at most {depth} instructions");
+            }
+            DbgCmd::ListWatchpoints => {
+                eprintln!("watchpoints:");
+                for (i, wp) in self.watchpoints.iter().enumerate() {
+                    eprintln!(
+                        "\t #{}: {} 0x{:04X} ({} bytes)",
+                        i + 1,
+                        wp.kind,
+                        wp.addr,
+                        wp.len
+                    );
+                }
+                if self.watchpoints.is_empty() {
+                    eprintln!("\t<no watchpoints set>");
+                }
+            }
+            DbgCmd::AddWatchpoint(kind, val) => {
+                let addr = self.eval_dbg_val_rvalue(val)?;
+                self.watchpoints.push(Watchpoint {
+                    addr,
+                    len: 2,
+                    kind: *kind,
+                });
+                eprintln!("added {kind} watchpoint at 0x{addr:04X}");
+            }
+            DbgCmd::RemoveWatchpoint(val) => {
+                let ordinal = self.eval_dbg_val_rvalue(val)? as usize;
+                if !(1..=self.watchpoints.len()).contains(&ordinal) {
+                    return Err(format!(
+                        "invalid watchpoint ordinal. Enter a value between 1 and {}.",
+                        self.watchpoints.len()
+                    ));
+                }
+                let wp = self.watchpoints.remove(ordinal - 1);
+                eprintln!(
+                    "removed watchpoint #{ordinal}: {} 0x{:04X}",
+                    wp.kind, wp.addr
+                );
+            }
+            DbgCmd::Backtrace => self.print_backtrace(),
+            DbgCmd::History => self.cmd_history.print(),
+            DbgCmd::Source(_) | DbgCmd::Define(_) => {
+                unreachable!("scripting commands are run by `run_dbg_line`")
+            }
+            DbgCmd::Frame(n) => self.print_frame(*n)?,
             DbgCmd::PrintRegs => {
+                // Highlight the registers which changed since the last pause.
+                let changed = self.changed_regs();
+                let print = |name: String, line: String| match changed.contains(&name) {
+                    true => eprintln!("{}", highlight(&line)),
+                    false => eprintln!("{line}"),
+                };
+
                 eprintln!("general-purpose registers:");
-                for (regname, regval) in self.regs.iter() {
-                    eprintln!("\t${regname} = 0x{v:04X} = {v}", v = regval.as_u16());
+                for (reg, regval) in self.regs.iter() {
+                    let v = regval.as_u16();
+                    print(reg.to_string(), format!("\t{reg} = 0x{v:04X} = {v}"));
                 }
                 eprintln!("special-purpose registers:");
-                eprintln!("\t${} = 0x{v:04X} = {v}", Spr::Lo, v = self.lo.as_u16());
-                eprintln!("\t${} = 0x{v:04X} = {v}", Spr::Hi, v = self.hi.as_u16());
-                eprintln!("\t${} = 0x{v:04X} = {v}", Spr::Pc, v = self.pc,);
+                for spr in [
+                    Spr::Lo,
+                    Spr::Hi,
+                    Spr::Pc,
+                    Spr::Status,
+                    Spr::Cause,
+                    Spr::Epc,
+                    Spr::BadAddr,
+                ] {
+                    let v = self.spr(spr) as u16;
+                    print(format!("${spr}"), format!("\t${spr} = 0x{v:04X} = {v}"));
+                }
                 eprintln!("\t${} = 0x{v:08X} = {v} = 0b{v:032b}", Spr::Ir, v = self.ir);
+                eprintln!("clock cycles: {}", self.cycles());
+            }
+            DbgCmd::Display(Some(expr)) => {
+                DbgVal::parse(&mut &expr[..]).map_err(|_| format!("invalid value `{expr}`"))?;
+                self.displays.push(expr.clone());
+                eprintln!("{}", self.display_line(self.displays.len(), expr));
+            }
+            DbgCmd::Display(None) => self.print_displays(),
+            DbgCmd::Undisplay(n) => {
+                if *n == 0 || *n > self.displays.len() {
+                    return Err(format!("no display number {n}"));
+                }
+                self.displays.remove(n - 1);
             }
         }
+        Ok(())
     }
 
-    fn eval_dbg_val_rvalue(&mut self, val: &DbgVal) -> u16 {
-        match val {
+    fn eval_dbg_val_rvalue(&mut self, val: &DbgVal) -> Result<u16, String> {
+        let value = match val {
             DbgVal::U16(val) => *val,
             DbgVal::Gpr(reg) => self.regs.get(*reg),
-            DbgVal::Spr(spr) => match spr {
-                Spr::Pc => self.pc,
-                Spr::Ir => unreachable!(),
-                Spr::Lo => self.lo.as_u16(),
-                Spr::Hi => self.hi.as_u16(),
-            },
+            DbgVal::Spr(Spr::Ir) => {
+                return Err("$ir is 32 bits wide, use `registers` to view it".into())
+            }
+            DbgVal::Spr(spr) => self.spr(*spr) as u16,
             DbgVal::Mem { base, offset } => {
-                let base = self.eval_dbg_val_rvalue(base);
-                let offset = self.eval_dbg_val_rvalue(offset) as i16;
-                self.mem_read_s16(base, offset).as_u16()
+                let base = self.eval_dbg_val_rvalue(base)?;
+                let offset = self.eval_dbg_val_rvalue(offset)? as i16;
+                let addr = debug_word_addr(base, offset)?;
+                self.mem.read_s16(addr).as_u16()
             }
-            DbgVal::Neg(val) => self.eval_dbg_val_rvalue(val).wrapping_neg(),
-        }
+            DbgVal::Neg(val) => self.eval_dbg_val_rvalue(val)?.wrapping_neg(),
+            DbgVal::Sym(name) => self
+                .symbols
+                .addr_of(name)
+                .ok_or_else(|| format!("unknown symbol `{name}`"))?,
+        };
+        Ok(value)
     }
 
     /// Returns the the previous value of the lvalue.
-    fn set_lvalue(&mut self, lhs: &DbgVal, rhs: u16) -> u16 {
-        match lhs {
+    fn set_lvalue(&mut self, lhs: &DbgVal, rhs: u16) -> Result<u16, String> {
+        let prev = match lhs {
             DbgVal::Gpr(reg) => {
                 let prev = self.regs.get(*reg);
                 self.regs.set(*reg, rhs);
                 prev
             }
-            DbgVal::Spr(Spr::Ir) => panic!("cannot assign to $ir"),
+            DbgVal::Spr(Spr::Ir) => return Err("cannot assign to $ir".into()),
             DbgVal::Spr(spr) => {
-                let prev = match spr {
-                    Spr::Pc => self.pc,
-                    Spr::Ir => unreachable!(),
-                    Spr::Lo => self.lo.as_u16(),
-                    Spr::Hi => self.hi.as_u16(),
-                };
-                match spr {
-                    Spr::Pc => self.pc = rhs,
-                    Spr::Ir => unreachable!(),
-                    Spr::Lo => *self.lo.as_u16_mut() = rhs,
-                    Spr::Hi => *self.hi.as_u16_mut() = rhs,
-                }
+                let prev = self.spr(*spr) as u16;
+                self.set_spr(*spr, rhs);
                 prev
             }
             DbgVal::Mem { base, offset } => {
-                let base = self.eval_dbg_val_rvalue(base);
-                let offset = s16::from(self.eval_dbg_val_rvalue(offset)).as_i16();
-                let prev = self.mem_read_s16(base, offset).as_u16();
-                self.mem_write_s16(base, offset, rhs.into());
+                let base = self.eval_dbg_val_rvalue(base)?;
+                let offset = s16::from(self.eval_dbg_val_rvalue(offset)?).as_i16();
+                let addr = debug_word_addr(base, offset)?;
+                let prev = self.mem.read_s16(addr).as_u16();
+                self.mem.write_s16(addr, rhs.into());
                 prev
             }
-            DbgVal::U16(_) | DbgVal::Neg(_) => panic!("cannot assign to rvalue"),
+            DbgVal::U16(_) | DbgVal::Neg(_) | DbgVal::Sym(_) => {
+                return Err("cannot assign to rvalue".into())
+            }
+        };
+        Ok(prev)
+    }
+
+    /// Prints `count` units of memory starting at `addr` in the given format.
+    fn print_examine(&self, addr: u16, count: u16, fmt: ExamineFmt) {
+        for row in self.examine(addr, count, fmt) {
+            eprintln!("{row}");
+        }
+    }
+
+    /// Formats `count` units of memory starting at `addr`, one row per line.
+    /// Units past the end of memory are left out.
+    fn examine(&self, addr: u16, count: u16, fmt: ExamineFmt) -> Vec<String> {
+        let per_row = fmt.units_per_row() as u32;
+        let unit_size = fmt.unit_size() as u32;
+        let count = (count as u32).min((0x1_0000 - addr as u32).div_ceil(unit_size));
+
+        let mut rows = Vec::new();
+        for row in 0..count.div_ceil(per_row) {
+            let row_addr = addr as u32 + row * per_row * unit_size;
+            let mut line = format!("0x{row_addr:04X}:");
+            if let ExamineFmt::Ascii = fmt {
+                line.push(' ');
+            }
+            let units = per_row.min(count - row * per_row);
+            for i in 0..units {
+                let unit_addr = (row_addr + i * unit_size) as u16;
+                if check_debug_access(unit_addr, unit_size as usize).is_err() {
+                    line.push_str(match fmt {
+                        ExamineFmt::Byte => " --",
+                        ExamineFmt::Word => " ----",
+                        ExamineFmt::Signed => " ------",
+                        ExamineFmt::Ascii => "?",
+                    });
+                    continue;
+                }
+                let unit = match fmt {
+                    ExamineFmt::Byte => format!(" {:02X}", self.mem.read_u8(unit_addr)),
+                    ExamineFmt::Word => format!(" {:04X}", self.mem.read_s16(unit_addr).as_u16()),
+                    ExamineFmt::Signed => format!(" {:+6}", self.mem.read_s16(unit_addr).as_i16()),
+                    ExamineFmt::Ascii => {
+                        let byte = self.mem.read_u8(unit_addr);
+                        if byte.is_ascii_graphic() || byte == b' ' {
+                            (byte as char).to_string()
+                        } else {
+                            ".".to_string()
+                        }
+                    }
+                };
+                line.push_str(&unit);
+            }
+            rows.push(line);
+        }
+        rows
+    }
+
+    /// Prints the values of the `display` expressions.
+    fn print_displays(&mut self) {
+        for line in self.display_lines() {
+            eprintln!("{line}");
         }
     }
 
+    /// Evaluates the `display` expressions.
+    fn display_lines(&mut self) -> Vec<String> {
+        let displays = self.displays.clone();
+        displays
+            .iter()
+            .enumerate()
+            .map(|(i, expr)| self.display_line(i + 1, expr))
+            .collect()
+    }
+
+    fn display_line(&mut self, n: usize, expr: &str) -> String {
+        let value = DbgVal::parse(&mut &expr[..])
+            .map_err(|e| e.to_string())
+            .and_then(|val| self.eval_dbg_val_rvalue(&val));
+        match value {
+            Ok(v) => format!("{n}: {expr} = 0x{v:04X} = {v}"),
+            Err(err) => format!("{n}: {expr} = <error: {err}>"),
+        }
+    }
+
+    /// Saves the registers at a pause, keeping those of the previous pause.
+    fn save_stop_regs(&mut self) {
+        self.prev_stop_regs = self.stop_regs.replace(RegSnapshot::of(self));
+    }
+
+    /// The registers which changed between the previous pause and now, like
+    /// `$t0` or `$lo`. Empty at the first pause.
+    fn changed_regs(&self) -> Vec<String> {
+        let Some(prev) = &self.prev_stop_regs else {
+            return Vec::new();
+        };
+        let gprs = self
+            .regs
+            .iter()
+            .filter(|&(reg, value)| prev.regs.get::<u16>(reg) != value.as_u16())
+            .map(|(reg, _)| reg.to_string());
+        let sprs = Spr::ALL
+            .into_iter()
+            .filter(|spr| {
+                prev.sprs
+                    .get(spr)
+                    .is_some_and(|&v| v != self.spr(*spr) as u16)
+            })
+            .map(|spr| format!("${spr}"));
+        gprs.chain(sprs).collect()
+    }
+
+    /// Prints the current instruction and the source it came from.
+    fn print_location(&self) {
+        if let Ok(instr) = Instr::from_bits(self.ir.view_bits::<Msb0>()) {
+            let sym = match self.symbols.describe(self.pc) {
+                Some(sym) => format!(" <{sym}>"),
+                None => String::new(),
+            };
+            eprintln!(
+                "=> 0x{:04X}{sym}:\t{}",
+                self.pc,
+                instr.display_at(self.pc, &self.symbols)
+            );
+        }
+        self.print_src_context(2);
+    }
+
+    /// Prints the source line of the current instruction along with `context`
+    /// lines above and below it. Does nothing if no source map is loaded.
+    fn print_src_context(&self, context: u32) {
+        let Some(loc) = self.src_loc(self.pc) else {
+            return;
+        };
+
+        eprintln!("at {loc}:");
+        let Ok(text) = std::fs::read_to_string(&loc.file) else {
+            eprintln!("\t<could not read `{}`>", loc.file.display());
+            return;
+        };
+
+        let first = loc.line.saturating_sub(context).max(1);
+        let last = loc.line.saturating_add(context);
+        for (lineno, src) in (1..).zip(text.lines()) {
+            if (first..=last).contains(&lineno) {
+                let marker = if lineno == loc.line { "=>" } else { "  " };
+                eprintln!("{marker}{lineno:5}  {src}");
+            }
+        }
+    }
+
+    fn print_backtrace(&self) {
+        let frames = self.call_stack.frames();
+        let callee = |i: usize| match i.checked_sub(1) {
+            Some(i) => self.symbols.fmt_addr(frames[i].callee),
+            None => "<entry>".to_string(),
+        };
+        let addr = |addr: u16| match self.symbols.describe(addr) {
+            Some(sym) => format!("0x{addr:04X} <{sym}>"),
+            None => format!("0x{addr:04X}"),
+        };
+        eprintln!("#0  {} in {}", addr(self.pc), callee(frames.len()));
+        for (n, i) in (0..frames.len()).rev().enumerate() {
+            let frame = &frames[i];
+            eprintln!(
+                "#{}  {} in {} (called from {})",
+                n + 1,
+                addr(frame.return_addr),
+                callee(i),
+                addr(frame.call_site),
+            );
+        }
+    }
+
+    /// Prints the words of the stack which belong to the `n`-th frame of the
+    /// backtrace. Frame 0 is the currently executing function.
+    fn print_frame(&self, n: usize) -> Result<(), String> {
+        /// Frames can be large (or the shadow stack can be wrong), so don't
+        /// print everything.
+        const MAX_WORDS: u16 = 32;
+
+        let frames = self.call_stack.frames();
+        if n > frames.len() {
+            return Err(format!(
+                "invalid frame number. Enter a value between 0 and {}.",
+                frames.len()
+            ));
+        }
+
+        // The stack grows downward, so the frame spans from the `$sp` at the
+        // time it made its most recent call up to the `$sp` when it was called.
+        let lo = match n {
+            0 => self.regs.get(Reg::Sp),
+            n => frames[frames.len() - n].sp,
+        };
+        // The top of the outermost frame is unknown if the frames of its
+        // callers were dropped from the shadow stack.
+        let hi = match (frames.len() - n).checked_sub(1) {
+            Some(i) => Some(frames[i].sp),
+            None if self.call_stack.is_truncated() => None,
+            None => Some(STACK_INIT),
+        };
+
+        let frame_start = match n {
+            0 => self.pc,
+            n => frames[frames.len() - n].return_addr,
+        };
+        let Some(hi) = hi else {
+            eprintln!(
+                "frame #{n} at 0x{frame_start:04X}, $sp range [0x{lo:04X}, ?): truncated, the \
+                 frames of its callers were dropped from the call stack"
+            );
+            return Ok(());
+        };
+        eprintln!("frame #{n} at 0x{frame_start:04X}, $sp range [0x{lo:04X}, 0x{hi:04X}):");
+
+        let mut addr = lo;
+        let mut words = 0;
+        while addr < hi && words < MAX_WORDS {
+            let value = self.mem.read_s16(addr).as_u16();
+            eprintln!("[0x{addr:04X}] = 0x{value:04X} = {value:06}");
+            addr = addr.wrapping_add(2);
+            words += 1;
+        }
+        if addr < hi {
+            eprintln!("...");
+        }
+        Ok(())
+    }
+
     fn print_stack(&self, depth: u16) {
-        let sp = self.regs.get(Reg::Sp);
-        let mut addr = sp;
-        for i in 0..depth {
-            let value = self.mem_read_s16(addr, 0).as_u16();
+        for (i, (_addr, value)) in self.stack_words(depth).enumerate() {
             eprintln!("[$sp+{:02}] = 0x{value:04X} = {value:06}", 2 * i);
-            addr += 2;
+        }
+    }
+
+    /// The address and value of the top `depth` words of the stack, stopping
+    /// early at the end of memory or at a device register.
+    pub fn stack_words(&self, depth: u16) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let sp: u16 = self.regs.get(Reg::Sp);
+        (0..depth).map_while(move |i| {
+            let addr = sp.checked_add(i.checked_mul(2)?)?;
+            check_debug_access(addr, 2).ok()?;
+            Some((addr, self.mem.read_s16(addr).as_u16()))
+        })
+    }
+
+    /// Reads a special-purpose register. Only `$ir` uses all 32 bits.
+    pub fn spr(&self, spr: Spr) -> u32 {
+        match spr {
+            Spr::Pc => self.pc as u32,
+            Spr::Ir => self.ir,
+            Spr::Lo => self.lo.as_u16() as u32,
+            Spr::Hi => self.hi.as_u16() as u32,
+            Spr::Status => self.status() as u32,
+            Spr::Cause => self.cause as u32,
+            Spr::Epc => self.interrupt_return_address as u32,
+            Spr::BadAddr => self.bad_addr as u32,
+        }
+    }
+
+    /// Writes a special-purpose register other than `$ir`.
+    pub fn set_spr(&mut self, spr: Spr, value: u16) {
+        match spr {
+            Spr::Pc => self.pc = value,
+            Spr::Ir => panic!("$ir can't be set to a 16-bit value"),
+            Spr::Lo => *self.lo.as_u16_mut() = value,
+            Spr::Hi => *self.hi.as_u16_mut() = value,
+            Spr::Status => self.set_status(value),
+            Spr::Cause => self.cause = value,
+            Spr::Epc => self.interrupt_return_address = value,
+            Spr::BadAddr => self.bad_addr = value,
         }
     }
 }
 
+/// Checks that the debugger can access `len` bytes at `addr`: they must fit in
+/// memory, and none of them may be a device register, since accessing those
+/// has side effects (or isn't supported at all).
+fn check_debug_access(addr: u16, len: usize) -> Result<(), String> {
+    if addr as usize + len > u16::MAX as usize + 1 {
+        return Err(format!("{len} bytes do not fit in memory at 0x{addr:04X}"));
+    }
+    match (addr..=u16::MAX)
+        .take(len)
+        .find(|&addr| Memory::is_device_register(addr))
+    {
+        Some(reg) => Err(format!(
+            "0x{reg:04X} is a device register, which the debugger can't access"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The address of the word at `base + offset`, if the debugger can access it.
+fn debug_word_addr(base: u16, offset: i16) -> Result<u16, String> {
+    let addr = base
+        .checked_add_signed(offset)
+        .ok_or_else(|| format!("address 0x{base:04X} + {offset} is out of range"))?;
+    check_debug_access(addr, 2)?;
+    Ok(addr)
+}
+
 #[derive(Debug, Clone)]
 enum DbgCmd {
     Eval(DbgVal),
     Set(DbgVal, DbgVal),
-    PrintStack { depth: u16 },
+    PrintStack {
+        depth: u16,
+    },
     ListBreakpoints,
     AddBreakpoint(DbgVal),
+    AddSrcBreakpoint {
+        file: PathBuf,
+        line: u32,
+    },
     RemoveBreakpoint(DbgVal),
     Continue,
+    /// Continue until execution reaches a different source line.
+    NextLine,
+    /// Print the source around the current line.
+    List {
+        context: u32,
+    },
     PrintRegs,
+    /// Print a range of memory in a particular format.
+    Examine {
+        count: u16,
+        fmt: ExamineFmt,
+        addr: DbgVal,
+    },
+    /// Print a string the same way `DEBUG_PUTS` would read it.
+    PrintStr {
+        addr: DbgVal,
+        len: DbgVal,
+    },
+    /// Write the same byte to a range of memory.
+    Fill {
+        addr: DbgVal,
+        len: DbgVal,
+        byte: DbgVal,
+    },
+    /// Copy the contents of a file into memory.
+    Load {
+        path: PathBuf,
+        addr: DbgVal,
+    },
+    /// Save a snapshot of the whole machine to a file.
+    SaveSnapshot(PathBuf),
+    /// Restore the whole machine from a snapshot file.
+    RestoreSnapshot(PathBuf),
+    /// Undo the given number of instructions.
+    ReverseStep(u16),
+    /// Undo instructions until reaching a breakpoint or watchpoint hit.
+    ReverseContinue,
+    /// Print or set the maximum number of undoable instructions.
+    HistoryDepth(Option<usize>),
+    ListWatchpoints,
+    AddWatchpoint(WatchKind, DbgVal),
+    RemoveWatchpoint(DbgVal),
+    /// Print the shadow call stack.
+    Backtrace,
+    /// Print the stack slice of the n-th frame of the backtrace.
+    Frame(usize),
+    /// Run the debugger commands in a file.
+    Source(PathBuf),
+    /// Define a command from the lines which follow, up to `end`.
+    Define(String),
+    /// Print the command history.
+    History,
+    /// Add a value to print every time execution pauses, or print them all.
+    Display(Option<String>),
+    /// Stop displaying the n-th value.
+    Undisplay(usize),
+}
+
+/// Register values saved when execution pauses, so the `registers` command
+/// can show which ones changed since the previous pause.
+pub(super) struct RegSnapshot {
+    regs: RegisterFile,
+    /// Every special-purpose register but `$ir`.
+    sprs: HashMap<Spr, u16>,
+}
+
+impl RegSnapshot {
+    fn of(cpu: &Cpu) -> Self {
+        Self {
+            regs: cpu.regs.clone(),
+            sprs: Spr::ALL
+                .into_iter()
+                .filter(|&spr| spr != Spr::Ir)
+                .map(|spr| (spr, cpu.spr(spr) as u16))
+                .collect(),
+        }
+    }
+}
+
+/// Highlights a line of output: in bold yellow on a terminal, or with a `*`
+/// otherwise.
+fn highlight(line: &str) -> String {
+    use std::io::IsTerminal;
+
+    if std::io::stderr().is_terminal() {
+        format!("\x1b[1;33m{line}\x1b[0m")
+    } else {
+        format!("*{line}")
+    }
+}
+
+/// The display format used by the `x/` (examine memory) command.
+#[derive(Debug, Clone, Copy)]
+enum ExamineFmt {
+    /// Bytes in hexadecimal.
+    Byte,
+    /// Words in hexadecimal.
+    Word,
+    /// Words as signed decimal integers.
+    Signed,
+    /// Bytes as ASCII characters.
+    Ascii,
+}
+
+impl ExamineFmt {
+    fn unit_size(self) -> u16 {
+        match self {
+            Self::Byte | Self::Ascii => 1,
+            Self::Word | Self::Signed => 2,
+        }
+    }
+
+    fn units_per_row(self) -> u16 {
+        match self {
+            Self::Byte => 16,
+            Self::Word | Self::Signed => 8,
+            Self::Ascii => 64,
+        }
+    }
 }
 
 impl DbgCmd {
     fn parse(s: &mut &str) -> winnow::PResult<Self> {
         use winnow::ascii::{dec_uint, multispace0, multispace1};
         use winnow::combinator::{alt, opt, preceded, separated_pair};
+        use winnow::token::{one_of, take_till};
         use winnow::Parser;
 
         alt((
+            // Try parsing the memory commands.
+            alt((
+                // Try parsing an examine memory command.
+                (
+                    preceded("x/", opt(dec_uint)),
+                    one_of(['b', 'w', 'd', 'c']).map(|c| match c {
+                        'b' => ExamineFmt::Byte,
+                        'w' => ExamineFmt::Word,
+                        'd' => ExamineFmt::Signed,
+                        _ => ExamineFmt::Ascii,
+                    }),
+                    preceded(multispace1, DbgVal::parse),
+                )
+                    .map(|(count, fmt, addr)| Self::Examine {
+                        count: count.unwrap_or(1),
+                        fmt,
+                        addr,
+                    }),
+                // Try parsing a print string command.
+                preceded(
+                    ("str", multispace1),
+                    separated_pair(DbgVal::parse, multispace1, DbgVal::parse),
+                )
+                .map(|(addr, len)| Self::PrintStr { addr, len }),
+                // Try parsing a fill memory command.
+                preceded(
+                    ("fill", multispace1),
+                    (
+                        DbgVal::parse,
+                        preceded(multispace1, DbgVal::parse),
+                        preceded(multispace1, DbgVal::parse),
+                    ),
+                )
+                .map(|(addr, len, byte)| Self::Fill { addr, len, byte }),
+                // Try parsing a load file command.
+                preceded(
+                    ("load", multispace1),
+                    separated_pair(
+                        take_till(1.., char::is_whitespace),
+                        multispace1,
+                        DbgVal::parse,
+                    ),
+                )
+                .map(|(path, addr): (&str, _)| Self::Load {
+                    path: PathBuf::from(path),
+                    addr,
+                }),
+                // Try parsing the snapshot commands.
+                preceded(
+                    ("snapshot", multispace1),
+                    alt((
+                        preceded(("save", multispace1), take_till(1.., char::is_whitespace))
+                            .map(|path: &str| Self::SaveSnapshot(PathBuf::from(path))),
+                        preceded(
+                            ("restore", multispace1),
+                            take_till(1.., char::is_whitespace),
+                        )
+                        .map(|path: &str| Self::RestoreSnapshot(PathBuf::from(path))),
+                    )),
+                ),
+            )),
+            // Try parsing the reverse execution commands.
+            preceded(
+                keyword(alt(("reverse-step", "rs"))),
+                opt(preceded(multispace1, dec_uint)),
+            )
+            .map(|count| Self::ReverseStep(count.unwrap_or(1))),
+            keyword(alt(("reverse-continue", "rc"))).map(|_| Self::ReverseContinue),
+            preceded(
+                keyword("history-depth"),
+                opt(preceded(multispace1, dec_uint)),
+            )
+            .map(|depth: Option<u32>| Self::HistoryDepth(depth.map(|d| d as usize))),
+            // Try parsing the watchpoint commands.
+            alt((
+                preceded(("watch", multispace1), DbgVal::parse)
+                    .map(|val| Self::AddWatchpoint(WatchKind::Write, val)),
+                preceded(("rwatch", multispace1), DbgVal::parse)
+                    .map(|val| Self::AddWatchpoint(WatchKind::Read, val)),
+                preceded(("awatch", multispace1), DbgVal::parse)
+                    .map(|val| Self::AddWatchpoint(WatchKind::Access, val)),
+                preceded(
+                    (alt(("-watchpoint", "-w")), multispace1, opt("#")),
+                    DbgVal::parse,
+                )
+                .map(Self::RemoveWatchpoint),
+                keyword(alt(("watchpoints", "w"))).map(|_| Self::ListWatchpoints),
+            )),
+            // Try parsing the scripting commands.
+            alt((
+                preceded(("source", multispace1), take_till(1.., char::is_whitespace))
+                    .map(|path: &str| Self::Source(PathBuf::from(path))),
+                preceded(("define", multispace1), take_till(1.., char::is_whitespace))
+                    .map(|name: &str| Self::Define(name.to_string())),
+                keyword("history").map(|_| Self::History),
+            )),
+            // Try parsing the display commands.
+            alt((
+                preceded(
+                    keyword("display"),
+                    opt(preceded(multispace1, take_till(1.., ['\n', '\r']))),
+                )
+                .map(|expr: Option<&str>| Self::Display(expr.map(|e| e.trim().to_string()))),
+                preceded((keyword("undisplay"), multispace1), dec_uint)
+                    .map(|n: u16| Self::Undisplay(n as usize)),
+            )),
+            // Try parsing a backtrace command.
+            keyword(alt(("backtrace", "bt"))).map(|_| Self::Backtrace),
+            preceded(keyword("frame"), opt(preceded(multispace1, dec_uint)))
+                .map(|n: Option<u16>| Self::Frame(n.unwrap_or(0) as usize)),
             // Try parsing an add breakpoint command.
             preceded(
-                (alt(("+b", "b", "breakpoint", "+breakpoint")), multispace1),
-                DbgVal::parse.map(Self::AddBreakpoint),
+                (
+                    alt(("+breakpoint", "+b", "breakpoint", "break", "b")),
+                    multispace1,
+                ),
+                alt((
+                    separated_pair(take_till(1.., [':', ' ', '\t']), ':', dec_uint).map(
+                        |(file, line): (&str, _)| Self::AddSrcBreakpoint {
+                            file: PathBuf::from(file),
+                            line,
+                        },
+                    ),
+                    DbgVal::parse.map(Self::AddBreakpoint),
+                )),
             ),
             // Try parsing a remove breakpoint command.
             preceded(
@@ -221,15 +986,23 @@ impl DbgCmd {
                 DbgVal::parse.map(Self::RemoveBreakpoint),
             ),
             // Try parsing a list breakpoints command.
-            alt(("b", "breakpoints")).map(|_| Self::ListBreakpoints),
-            alt(("c", "continue")).map(|_| Self::Continue),
-            alt(("r", "regs", "registers")).map(|_| Self::PrintRegs),
+            keyword(alt(("breakpoints", "b"))).map(|_| Self::ListBreakpoints),
+            keyword(alt(("continue", "c"))).map(|_| Self::Continue),
+            keyword(alt(("next", "n"))).map(|_| Self::NextLine),
+            preceded(
+                keyword(alt(("list", "l"))),
+                opt(preceded(multispace1, dec_uint)),
+            )
+            .map(|context| Self::List {
+                context: context.unwrap_or(5),
+            }),
+            keyword(alt(("registers", "regs", "r"))).map(|_| Self::PrintRegs),
             // Try parsing a print stack command.
-            preceded((alt(("s", "stack")), multispace0), opt(dec_uint)).map(|val: Option<u16>| {
-                Self::PrintStack {
+            preceded((keyword(alt(("stack", "s"))), multispace0), opt(dec_uint)).map(
+                |val: Option<u16>| Self::PrintStack {
                     depth: val.unwrap_or(4),
-                }
-            }),
+                },
+            ),
             // Try parsing a set command.
             separated_pair(
                 DbgVal::parse,
@@ -244,6 +1017,17 @@ impl DbgCmd {
     }
 }
 
+/// Parses a command name which must not be immediately followed by more
+/// identifier characters (so that `l` doesn't match the start of `lo`).
+fn keyword<'s, O>(
+    name: impl winnow::Parser<&'s str, O, winnow::error::ContextError>,
+) -> impl winnow::Parser<&'s str, O, winnow::error::ContextError> {
+    use winnow::combinator::{not, terminated};
+    use winnow::token::one_of;
+
+    terminated(name, not(one_of(|c: char| c.is_alphanumeric() || c == '_')))
+}
+
 #[derive(Debug, Clone)]
 enum DbgVal {
     /// The value held in a general-purpose register.
@@ -259,6 +1043,8 @@ enum DbgVal {
     U16(u16),
     /// The negation of a value.
     Neg(Box<DbgVal>),
+    /// The address of a label from the symbol table.
+    Sym(String),
 }
 
 impl DbgVal {
@@ -266,6 +1052,7 @@ impl DbgVal {
         use winnow::{
             ascii::{dec_uint, hex_uint},
             combinator::{alt, delimited, opt, preceded},
+            token::{one_of, take_while},
             Parser,
         };
 
@@ -289,9 +1076,9 @@ impl DbgVal {
             }),
             // Try parsing an integer.
             alt((preceded("0x", hex_uint), dec_uint)).map(Self::U16),
-            // Try parsing a register.
+            // A `$` means it must be a register.
             preceded(
-                opt('$'),
+                '$',
                 alt((
                     // Try parsing a general-purpose register.
                     alt(Reg::NAMES).parse_to().map(Self::Gpr),
@@ -299,17 +1086,54 @@ impl DbgVal {
                     alt(SPR_NAMES).parse_to().map(Self::Spr),
                 )),
             ),
+            // Otherwise try parsing a register name, falling back to a symbol.
+            (
+                one_of(|c: char| c.is_alphabetic() || c == '_' || c == '.'),
+                take_while(0.., |c: char| c.is_alphanumeric() || c == '_' || c == '.'),
+            )
+                .recognize()
+                .map(|name: &str| {
+                    if let Ok(reg) = name.parse() {
+                        Self::Gpr(reg)
+                    } else if let Ok(spr) = name.parse() {
+                        Self::Spr(spr)
+                    } else {
+                        Self::Sym(name.to_string())
+                    }
+                }),
         ))
         .parse_next(s)
     }
 }
 
-#[derive(Debug, Clone)]
-enum Spr {
+/// A special-purpose register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Spr {
     Pc,
     Ir,
     Lo,
     Hi,
+    /// Interrupt enable bits (see [`interrupts`](super::interrupts)).
+    Status,
+    /// Why the current interrupt was taken.
+    Cause,
+    /// Where `kret` returns to.
+    Epc,
+    /// The address involved in the most recent fault.
+    BadAddr,
+}
+
+impl Spr {
+    pub const ALL: [Spr; 8] = [
+        Spr::Pc,
+        Spr::Ir,
+        Spr::Lo,
+        Spr::Hi,
+        Spr::Status,
+        Spr::Cause,
+        Spr::Epc,
+        Spr::BadAddr,
+    ];
 }
 
 impl std::fmt::Display for Spr {
@@ -319,12 +1143,16 @@ impl std::fmt::Display for Spr {
             Self::Ir => "ir",
             Self::Lo => "lo",
             Self::Hi => "hi",
+            Self::Status => "status",
+            Self::Cause => "cause",
+            Self::Epc => "epc",
+            Self::BadAddr => "badaddr",
         };
         write!(f, "{}", name)
     }
 }
 
-const SPR_NAMES: [&str; 4] = ["pc", "ir", "lo", "hi"];
+const SPR_NAMES: [&str; 8] = ["pc", "ir", "lo", "hi", "status", "cause", "epc", "badaddr"];
 
 impl FromStr for Spr {
     type Err = String;
@@ -335,7 +1163,114 @@ impl FromStr for Spr {
             "ir" => Ok(Self::Ir),
             "lo" => Ok(Self::Lo),
             "hi" => Ok(Self::Hi),
+            "status" => Ok(Self::Status),
+            "cause" => Ok(Self::Cause),
+            "epc" => Ok(Self::Epc),
+            "badaddr" => Ok(Self::BadAddr),
             _ => Err(format!("invalid special-purpose register name: `{}`", s)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::MemBlock;
+
+    fn run(cpu: &mut Cpu, line: &str) -> Result<(), String> {
+        let cmd = DbgCmd::parse(&mut &line[..]).map_err(|e| e.to_string())?;
+        cpu.eval_dbg_cmd(&cmd)
+    }
+
+    #[test]
+    fn memory_commands_avoid_device_registers() {
+        let mut cpu = Cpu::headless(MemBlock::from_vec(vec![0x04]).unwrap());
+
+        run(&mut cpu, "fill 0x0900 3 0x07").unwrap();
+        assert_eq!(cpu.mem.read_s16(0x0900).as_u16(), 0x0707);
+        for line in ["fill 0x0018 2 0", "str 0x0010 8", "[0x0018] = 1"] {
+            assert!(run(&mut cpu, line).unwrap_err().contains("device register"));
+        }
+        assert!(run(&mut cpu, "[0xFFFF]")
+            .unwrap_err()
+            .contains("do not fit"));
+        assert!(run(&mut cpu, "[0xFFFF+4]")
+            .unwrap_err()
+            .contains("out of range"));
+        assert!(check_debug_access(0x0000, 0x1_0000).is_err());
+
+        // Unreadable units are skipped rather than read.
+        assert_eq!(
+            cpu.examine(0x0010, 4, ExamineFmt::Word),
+            ["0x0010: ---- ---- ---- ----"]
+        );
+        assert_eq!(
+            cpu.examine(0x08FF, 3, ExamineFmt::Byte),
+            ["0x08FF: 00 07 07"]
+        );
+        assert_eq!(
+            cpu.examine(0xFFFD, 4, ExamineFmt::Word),
+            ["0xFFFD: 0000 ----"]
+        );
+
+        // Counts larger than the address space stop at the end of memory.
+        run(&mut cpu, "x/40000w 0").unwrap();
+        let rows = cpu.examine(0, 40000, ExamineFmt::Word);
+        assert_eq!(rows.len(), 0x1_0000 / 16);
+        assert!(rows[1].starts_with("0x0010: ---- ----"));
+        assert_eq!(
+            rows.last().unwrap(),
+            "0xFFF0: 0000 0000 0000 0000 0000 0000 0000 0000"
+        );
+        assert_eq!(cpu.examine(0xFFF0, u16::MAX, ExamineFmt::Byte).len(), 1);
+    }
+
+    #[test]
+    fn displays_and_changed_registers_at_each_stop() {
+        let mut cpu = Cpu::headless(MemBlock::from_vec(vec![0x04]).unwrap());
+        run(&mut cpu, "$t0 = 1").unwrap();
+        run(&mut cpu, "display $t0").unwrap();
+        run(&mut cpu, "display [0x0900]").unwrap();
+
+        cpu.save_stop_regs();
+        assert_eq!(
+            cpu.display_lines(),
+            ["1: $t0 = 0x0001 = 1", "2: [0x0900] = 0x0000 = 0"]
+        );
+        assert!(cpu.changed_regs().is_empty());
+
+        run(&mut cpu, "$t0 = 2").unwrap();
+        run(&mut cpu, "[0x0900] = 7").unwrap();
+        cpu.lo = s16::from(5u16);
+        cpu.hi = s16::from(6u16);
+        cpu.pc = 0x0802;
+        cpu.save_stop_regs();
+        assert_eq!(
+            cpu.display_lines(),
+            ["1: $t0 = 0x0002 = 2", "2: [0x0900] = 0x0007 = 7"]
+        );
+        assert_eq!(cpu.changed_regs(), ["$t0", "$pc", "$lo", "$hi"]);
+
+        cpu.save_stop_regs();
+        assert!(cpu.changed_regs().is_empty());
+    }
+
+    #[test]
+    fn snapshot_commands() {
+        let mut cpu = Cpu::headless(MemBlock::from_vec(vec![0x04]).unwrap());
+        let path = std::env::temp_dir().join(format!("lark-snapshot-{}", std::process::id()));
+        let path = path.display();
+
+        run(&mut cpu, "$t0 = 7").unwrap();
+        run(&mut cpu, &format!("snapshot save {path}")).unwrap();
+        run(&mut cpu, "$t0 = 8").unwrap();
+        run(&mut cpu, &format!("snapshot restore {path}")).unwrap();
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 7);
+
+        // Loading a raw file needs an address, and never restores a snapshot.
+        run(&mut cpu, "$t0 = 8").unwrap();
+        assert!(run(&mut cpu, &format!("load {path}")).is_err());
+        std::fs::remove_file(path.to_string()).unwrap();
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 8);
+    }
+}
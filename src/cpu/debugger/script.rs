@@ -0,0 +1,411 @@
+//! Debugger command scripts, user-defined commands and command history.
+//!
+//! A script is a text file with one debugger command per line, as they would
+//! be typed at the `debug>` prompt. Blank lines and lines starting with `#`
+//! are ignored. A user-defined command is written like in GDB:
+//!
+//! ```text
+//! define regs-and-stack
+//! registers
+//! stack 8
+//! end
+//! ```
+//!
+//! At the prompt, lines are read with a line editor, and the command history
+//! can be recalled with the arrow keys.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use rustyline::{error::ReadlineError, Config, DefaultEditor};
+
+use super::{Cpu, DbgCmd};
+
+/// Limits how deeply scripts and user-defined commands can invoke each other,
+/// so that a recursive definition doesn't overflow the stack.
+const MAX_NESTING: usize = 16;
+
+/// Where debugger commands are read from.
+pub(super) enum DbgInput {
+    /// Interactively, from a line editor.
+    Editor(Box<DefaultEditor>),
+    /// Interactively, from stdin, if the line editor isn't available.
+    Stdin,
+    /// From a script or a user-defined command.
+    Lines {
+        lines: std::vec::IntoIter<String>,
+        lineno: usize,
+    },
+}
+
+impl DbgInput {
+    /// Reads from the terminal, with the command history available for
+    /// editing. The line editor is created at the first pause and reused
+    /// after that; hand it back with [`finish`](DbgInput::finish).
+    pub(super) fn interactive(history: &mut CmdHistory) -> Self {
+        history.load();
+        if let Some(editor) = history.editor.take() {
+            return Self::Editor(editor);
+        }
+        let config = Config::builder()
+            .max_history_size(CmdHistory::MAX_ENTRIES)
+            .map(|builder| builder.build());
+        let editor = config.and_then(DefaultEditor::with_config);
+        let Ok(mut editor) = editor else {
+            return Self::Stdin;
+        };
+        for entry in &history.entries {
+            let _ = editor.add_history_entry(entry.as_str());
+        }
+        Self::Editor(Box::new(editor))
+    }
+
+    /// Keeps the line editor, if any, for the next pause.
+    pub(super) fn finish(self, history: &mut CmdHistory) {
+        if let Self::Editor(editor) = self {
+            history.editor = Some(editor);
+        }
+    }
+
+    fn from_lines(lines: Vec<String>) -> Self {
+        Self::Lines {
+            lines: lines.into_iter(),
+            lineno: 0,
+        }
+    }
+
+    /// Reads the next line, prompting for it if interactive. Returns `None`
+    /// at the end of the input.
+    pub(super) fn next_line(&mut self, prompt: &str) -> Option<String> {
+        match self {
+            Self::Editor(editor) => loop {
+                match editor.readline(prompt) {
+                    Ok(line) => return Some(line),
+                    // Ctrl-C discards the line being typed.
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(_) => return None,
+                }
+            },
+            Self::Stdin => {
+                eprint!("{prompt}");
+                io::stdout().flush().unwrap();
+                let mut line = String::new();
+                match io::stdin().lock().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line),
+                }
+            }
+            Self::Lines { lines, lineno } => {
+                *lineno += 1;
+                lines.next()
+            }
+        }
+    }
+
+    /// Makes a line entered at the prompt available for editing.
+    fn add_history(&mut self, line: &str) {
+        if let Self::Editor(editor) = self {
+            let _ = editor.add_history_entry(line);
+        }
+    }
+}
+
+/// Commands entered at the prompt, optionally saved to a file so they are
+/// remembered between sessions.
+#[derive(Default)]
+pub struct CmdHistory {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+    /// Whether the entries saved in `path` have been read.
+    loaded: bool,
+    /// The line editor used at the prompt, kept between pauses.
+    editor: Option<Box<DefaultEditor>>,
+}
+
+impl CmdHistory {
+    /// The number of commands remembered.
+    const MAX_ENTRIES: usize = 1000;
+
+    /// The history file used when none is given: `~/.lark_vm_history`.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".lark_vm_history"))
+    }
+
+    /// Remembers commands in `path`. The history saved there is only read
+    /// once the prompt is first shown, so that programs run without the
+    /// debugger don't touch the file.
+    pub fn with_path(path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            entries: Vec::new(),
+            loaded: false,
+            editor: None,
+        }
+    }
+
+    /// Reads the history saved in the file, if that hasn't been done yet.
+    fn load(&mut self) {
+        if std::mem::replace(&mut self.loaded, true) {
+            return;
+        }
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let mut entries: Vec<String> = std::fs::read_to_string(path)
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+
+        if entries.len() > Self::MAX_ENTRIES {
+            entries.drain(..entries.len() - Self::MAX_ENTRIES);
+            let _ = std::fs::write(path, entries.join("\n") + "\n");
+        }
+
+        entries.append(&mut self.entries);
+        self.entries = entries;
+    }
+
+    fn push(&mut self, line: &str) {
+        if self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.to_string());
+
+        if let Some(path) = &self.path {
+            // Failing to save history shouldn't interrupt debugging.
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+        }
+    }
+
+    /// Expands `!!` (the previous command) and `!<n>` (the `n`-th command).
+    /// Returns `None` if `line` isn't a history reference.
+    fn expand(&self, line: &str) -> Result<Option<String>, String> {
+        let Some(reference) = line.strip_prefix('!') else {
+            return Ok(None);
+        };
+
+        let entry = if reference == "!" {
+            self.entries.last()
+        } else {
+            let n: usize = reference
+                .parse()
+                .map_err(|_| format!("invalid history reference `{line}`"))?;
+            n.checked_sub(1).and_then(|i| self.entries.get(i))
+        };
+
+        entry
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| format!("no command `{line}` in history"))
+    }
+
+    pub(super) fn print(&mut self) {
+        self.load();
+        for (i, entry) in self.entries.iter().enumerate() {
+            eprintln!("{:5}  {entry}", i + 1);
+        }
+    }
+}
+
+impl Cpu {
+    /// Runs the debugger commands in a script file. Returns whether one of
+    /// them resumed execution (like `continue`), which ends the script.
+    pub fn source_debug_script(&mut self, path: &Path) -> Result<bool, String> {
+        self.source_nested(path, 0)
+    }
+
+    fn source_nested(&mut self, path: &Path, depth: usize) -> Result<bool, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read script `{}`: {e}", path.display()))?;
+        let mut input = DbgInput::from_lines(text.lines().map(str::to_string).collect());
+
+        self.run_dbg_lines(&mut input, depth)
+            .map_err(|e| match input {
+                DbgInput::Lines { lineno, .. } => format!("{}:{lineno}: {e}", path.display()),
+                DbgInput::Editor(_) | DbgInput::Stdin => e,
+            })
+    }
+
+    fn run_dbg_lines(&mut self, input: &mut DbgInput, depth: usize) -> Result<bool, String> {
+        while let Some(line) = input.next_line("") {
+            if self.run_dbg_line(&line, input, depth)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reads a line from the prompt, expanding and recording it in the command
+    /// history. Returns `None` at the end of the input.
+    pub(super) fn read_prompt_line(&mut self, input: &mut DbgInput) -> Option<String> {
+        loop {
+            let line = input.next_line("debug> ")?;
+            let line = line.trim();
+
+            match self.cmd_history.expand(line) {
+                Ok(Some(expanded)) => {
+                    eprintln!("{expanded}");
+                    self.cmd_history.push(&expanded);
+                    input.add_history(&expanded);
+                    return Some(expanded);
+                }
+                Ok(None) => {
+                    if !line.is_empty() {
+                        self.cmd_history.push(line);
+                        input.add_history(line);
+                    }
+                    return Some(line.to_string());
+                }
+                Err(err) => eprintln!("error: {err}"),
+            }
+        }
+    }
+
+    /// Runs a single line of debugger input, which may read more lines from
+    /// `input` (for `define`). Returns whether execution was resumed.
+    pub(super) fn run_dbg_line(
+        &mut self,
+        line: &str,
+        input: &mut DbgInput,
+        depth: usize,
+    ) -> Result<bool, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(false);
+        }
+
+        if depth > MAX_NESTING {
+            return Err("scripts and user-defined commands are nested too deeply".into());
+        }
+
+        let name = line.split_whitespace().next().unwrap_or_default();
+        if let Some(body) = self.dbg_macros.get(name) {
+            let mut body = DbgInput::from_lines(body.clone());
+            return self
+                .run_dbg_lines(&mut body, depth + 1)
+                .map_err(|e| format!("in `{name}`: {e}"));
+        }
+
+        let cmd = DbgCmd::parse(&mut &line[..]).map_err(|e| e.to_string())?;
+        match cmd {
+            DbgCmd::Source(path) => self.source_nested(&path, depth + 1),
+            DbgCmd::Define(name) => {
+                let mut body = Vec::new();
+                loop {
+                    let Some(line) = input.next_line(">") else {
+                        return Err(format!("missing `end` for `define {name}`"));
+                    };
+                    if line.trim() == "end" {
+                        break;
+                    }
+                    body.push(line.trim().to_string());
+                }
+                self.dbg_macros.insert(name, body);
+                Ok(false)
+            }
+            cmd => {
+                self.eval_dbg_cmd(&cmd)?;
+                Ok(matches!(cmd, DbgCmd::Continue | DbgCmd::NextLine))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustyline::history::History;
+
+    use super::*;
+    use crate::cpu::{regs::Reg, MemBlock};
+
+    #[test]
+    fn expand_history() {
+        let mut history = CmdHistory::default();
+        assert_eq!(history.expand("registers"), Ok(None));
+        assert!(history.expand("!!").is_err());
+
+        history.push("registers");
+        history.push("stack 8");
+        history.push("stack 8");
+        assert_eq!(history.expand("!!"), Ok(Some("stack 8".to_string())));
+        assert_eq!(history.expand("!1"), Ok(Some("registers".to_string())));
+        assert_eq!(history.expand("!2"), Ok(Some("stack 8".to_string())));
+        assert!(history.expand("!3").is_err());
+        assert!(history.expand("!0").is_err());
+        assert!(history.expand("!x").is_err());
+    }
+
+    #[test]
+    fn load_history_lazily() {
+        let path = std::env::temp_dir().join(format!("lark-history-{}", std::process::id()));
+        std::fs::write(&path, "registers\nstack\n").unwrap();
+
+        let mut history = CmdHistory::with_path(path.clone());
+        assert!(history.entries.is_empty());
+        history.load();
+        history.push("list");
+        assert_eq!(history.expand("!2"), Ok(Some("stack".to_string())));
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "registers\nstack\nlist\n");
+    }
+
+    #[test]
+    fn editor_is_reused() {
+        let mut history = CmdHistory::default();
+        history.push("registers");
+
+        let DbgInput::Editor(editor) = DbgInput::interactive(&mut history) else {
+            panic!("expected a line editor");
+        };
+        assert_eq!(editor.history().len(), 1);
+        let first: *const DefaultEditor = &*editor;
+        DbgInput::Editor(editor).finish(&mut history);
+
+        let input = DbgInput::interactive(&mut history);
+        assert!(history.editor.is_none());
+        let DbgInput::Editor(editor) = &input else {
+            panic!("expected a line editor");
+        };
+        assert!(std::ptr::eq(first, &**editor));
+        input.finish(&mut history);
+        assert!(history.editor.is_some());
+    }
+
+    #[test]
+    fn define_command() {
+        let rom = MemBlock::from_vec(vec![0x04]).unwrap();
+        let mut cpu = Cpu::headless(rom);
+        let mut input = DbgInput::from_lines(vec![
+            "define set-regs".to_string(),
+            "$t0 = 2".to_string(),
+            "$t1 = 3".to_string(),
+            "end".to_string(),
+            "set-regs".to_string(),
+        ]);
+
+        assert_eq!(cpu.run_dbg_lines(&mut input, 0), Ok(false));
+        assert_eq!(cpu.dbg_macros["set-regs"], ["$t0 = 2", "$t1 = 3"]);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 2);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T1), 3);
+
+        let mut input = DbgInput::from_lines(vec!["$t0 = 4".to_string()]);
+        let result = cpu.run_dbg_line("define unfinished", &mut input, 0);
+        assert_eq!(
+            result,
+            Err("missing `end` for `define unfinished`".to_string())
+        );
+
+        let mut input = DbgInput::from_lines(vec!["recurse".to_string(), "end".to_string()]);
+        assert_eq!(cpu.run_dbg_line("define recurse", &mut input, 0), Ok(false));
+        assert!(cpu.run_dbg_line("recurse", &mut input, 0).is_err());
+    }
+}
@@ -247,7 +247,7 @@ impl Instr {
 }
 
 const fn ceil_div(a: usize, b: usize) -> usize {
-    (a + b - 1) / b
+    a.div_ceil(b)
 }
 
 #[cfg(test)]
@@ -5,7 +5,9 @@ use bitvec::prelude::*;
 use crate::{cpu::decode, log_instr, utils::s16};
 
 use super::{
+    call_stack::CallFrame,
     instr::{ops::*, Instr},
+    interrupts::SYSTEM_REGS,
     regs::Reg,
     Cpu, Signal,
 };
@@ -27,36 +29,29 @@ impl Cpu {
         let ir = self.ir.view_bits::<Msb0>();
         let instr = Instr::from_bits(ir)?;
         let size = instr.instr_size();
+        self.charge_instr(&instr);
 
         match instr {
             Instr::O { opcode } => match opcode {
                 OpcodeOp::HALT => {
-                    self.log(log_instr!([size] halt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] halt));
                     self.signal(Signal::Halt);
                 }
                 OpcodeOp::NOP => {
-                    self.log(log_instr!([size] nop));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] nop));
                     self.pc += 1;
                 }
                 OpcodeOp::KRET => {
-                    self.log(log_instr!([size] kret));
-                    self.breakpoint();
-                    // Re-enable interrupts.
-                    self.interrupts_enabled = true;
-                    // Restore the PC from the K0 register.
-                    self.pc = self.regs.get(Reg::K0);
+                    self.log(log_instr!([self.pc, size] kret));
+                    self.kret();
                 }
                 OpcodeOp::INRE => {
-                    self.log(log_instr!([size] inre));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] inre));
                     self.interrupts_enabled = true;
                     self.pc += 1;
                 }
                 OpcodeOp::INRD => {
-                    self.log(log_instr!([size] inrd));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] inrd));
                     self.interrupts_enabled = false;
                     self.pc += 1;
                 }
@@ -64,8 +59,7 @@ impl Cpu {
 
             Instr::A { opcode, offset } => match opcode {
                 OpcodeAddr::J => {
-                    self.log(log_instr!([size] j offset));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] j offset));
                     self.pc = (self.pc as i32)
                         .checked_add(offset.as_i16() as i32)
                         .expect("Jump address overflow") as u16;
@@ -74,53 +68,79 @@ impl Cpu {
 
             Instr::I { opcode, imm10 } => match opcode {
                 OpcodeImm::EXN => {
-                    self.log(log_instr!([size] exn imm10));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] exn imm10));
                     self.handle_exn(imm10.as_u16());
                     self.pc += size;
                 }
-                OpcodeImm::KCALL => unimplemented!(),
+                OpcodeImm::KCALL => {
+                    self.log(log_instr!([self.pc, size] kcall imm10));
+                    self.handle_kcall(imm10.as_u16());
+                    self.pc += size;
+                }
             },
 
             Instr::R { opcode, reg } => match opcode {
                 OpcodeReg::JR => {
                     let rs = reg;
-                    self.log(log_instr!([size] jr rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] jr rs));
                     self.pc = self.regs.get(rs);
+                    if rs == Reg::Ra {
+                        if let Some(change) = self.call_stack.ret(self.pc) {
+                            self.history.record_call_stack(change);
+                        }
+                    }
                 }
                 OpcodeReg::MVLO => {
                     let rd = reg;
-                    self.log(log_instr!([size] mvlo rd));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] mvlo rd));
                     self.regs.set(rd, self.lo);
                     self.pc += size;
                 }
                 OpcodeReg::MVHI => {
                     let rd = reg;
-                    self.log(log_instr!([size] mvhi rd));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] mvhi rd));
                     self.regs.set(rd, self.hi);
                     self.pc += size;
                 }
             },
 
             Instr::RI { opcode, reg, imm } => match opcode {
+                OpcodeRegImm::MFSR => {
+                    let (rd, spr) = (reg, imm.as_u16());
+                    self.log(log_instr!([self.pc, size] mfsr rd, spr));
+                    let Some(&spr) = SYSTEM_REGS.get(spr as usize) else {
+                        // Fault at this instruction, rather than after it.
+                        self.illegal_instr();
+                        return Ok(());
+                    };
+                    self.regs.set(rd, self.spr(spr) as u16);
+                    self.pc += size;
+                }
+                OpcodeRegImm::MTSR => {
+                    let (rs, spr) = (reg, imm.as_u16());
+                    self.log(log_instr!([self.pc, size] mtsr rs, spr));
+                    let Some(&spr) = SYSTEM_REGS.get(spr as usize) else {
+                        self.illegal_instr();
+                        return Ok(());
+                    };
+                    self.set_spr(spr, self.regs.get(rs));
+                    self.pc += size;
+                }
                 OpcodeRegImm::JAL => {
                     // Jump and link.
                     // Example: jal $rd, ADDR
                     let (rd, offset) = (reg, imm.as_i16());
-                    self.log(log_instr!([size] jal rd, offset));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] jal rd, offset));
                     self.regs.set(rd, self.pc + size);
-                    self.pc = (self.pc as i32)
+                    let target = (self.pc as i32)
                         .checked_add(offset as i32)
                         .expect("Jump address overflow") as u16;
+                    self.record_call(rd, target, size);
+                    self.pc = target;
                 }
                 OpcodeRegImm::BT => {
                     let (rs, addr_offset) = (reg, imm.as_i16());
-                    self.log(log_instr!([size] bt rs, addr_offset));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] bt rs, addr_offset));
                     if self.regs.get(rs) {
                         self.pc = (self.pc as i32 + addr_offset as i32) as u16;
                     } else {
@@ -129,8 +149,7 @@ impl Cpu {
                 }
                 OpcodeRegImm::BF => {
                     let (rs, addr_offset) = (reg, imm.as_i16());
-                    self.log(log_instr!([size] bf rs, addr_offset));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] bf rs, addr_offset));
                     if !self.regs.get::<bool>(rs) {
                         self.pc = (self.pc as i32 + addr_offset as i32) as u16;
                     } else {
@@ -139,8 +158,7 @@ impl Cpu {
                 }
                 OpcodeRegImm::LI => {
                     let (rd, simm16) = (reg, imm.as_i16());
-                    self.log(log_instr!([size] li rd, simm16));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] li rd, simm16));
                     self.regs.set(rd, simm16);
                     self.pc += size;
                 }
@@ -153,26 +171,25 @@ impl Cpu {
                     //                |    |
                     //          save pc    jump address
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] jral rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] jral rd, rs));
                     self.regs.set(rd, self.pc + size);
-                    self.pc = self.regs.get(rs);
+                    let target = self.regs.get(rs);
+                    self.record_call(rd, target, size);
+                    self.pc = target;
                 }
                 OpcodeRegReg::MV => {
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] mv rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] mv rd, rs));
                     let value: s16 = self.regs.get(rs);
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegReg::MUL => {
                     let (rs, rt) = (reg1, reg2);
-                    self.log(log_instr!([size] mul rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] mul rs, rt));
 
                     let product = self.regs.get::<i16>(rs) as i32 * self.regs.get::<i16>(rt) as i32;
-                    let product = unsafe { std::mem::transmute::<i32, u32>(product) };
+                    let product = product as u32;
                     let product: &BitSlice<u32, Lsb0> = product.view_bits();
 
                     *self.lo.as_i16_mut() = product[0..16].load();
@@ -182,8 +199,7 @@ impl Cpu {
                 }
                 OpcodeRegReg::MULU => {
                     let (rs, rt) = (reg1, reg2);
-                    self.log(log_instr!([size] mulu rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] mulu rs, rt));
 
                     let product: u32 =
                         self.regs.get::<u16>(rs) as u32 * self.regs.get::<u16>(rt) as u32;
@@ -198,42 +214,37 @@ impl Cpu {
                 OpcodeRegReg::DIVU => unimplemented!(),
                 OpcodeRegReg::NOT => {
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] not rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] not rd, rs));
                     let value = !self.regs.get::<bool>(rs);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegReg::NEG => {
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] neg rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] neg rd, rs));
                     let value = -self.regs.get::<i16>(rs);
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegReg::SEB => {
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] seb rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] seb rd, rs));
                     let value = (self.regs.get::<u16>(rs) & 0x00FF) as u8;
-                    let value = unsafe { std::mem::transmute::<u8, i8>(value) };
+                    let value = value as i8;
                     let value = value as i16;
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegReg::TEZ => {
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] tez rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] tez rd, rs));
                     let value = self.regs.get::<u16>(rs) == 0u16;
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegReg::TNZ => {
                     let (rd, rs) = (reg1, reg2);
-                    self.log(log_instr!([size] tnz rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] tnz rd, rs));
                     let value = self.regs.get::<u16>(rs) != 0u16;
                     self.regs.set(rd, value as u16);
                     self.pc += size;
@@ -247,8 +258,7 @@ impl Cpu {
                 reg3: rt,
             } => match opcode {
                 OpcodeRegRegReg::ADD => {
-                    self.log(log_instr!([size] add rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] add rd, rs, rt));
                     let x = self.regs.get::<i16>(rs);
                     let y = self.regs.get::<i16>(rt);
                     let sum: i16 = x.wrapping_add(y);
@@ -256,8 +266,7 @@ impl Cpu {
                     self.pc += size;
                 }
                 OpcodeRegRegReg::ADDU => {
-                    self.log(log_instr!([size] addu rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] addu rd, rs, rt));
                     let x = self.regs.get::<u16>(rs);
                     let y = self.regs.get::<u16>(rt);
                     let sum: u16 = x.wrapping_add(y);
@@ -265,8 +274,7 @@ impl Cpu {
                     self.pc += size;
                 }
                 OpcodeRegRegReg::SUB => {
-                    self.log(log_instr!([size] sub rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] sub rd, rs, rt));
                     let x = self.regs.get::<i16>(rs);
                     let y = self.regs.get::<i16>(rt);
                     let diff: i16 = x.wrapping_sub(y);
@@ -274,8 +282,7 @@ impl Cpu {
                     self.pc += size;
                 }
                 OpcodeRegRegReg::SUBU => {
-                    self.log(log_instr!([size] subu rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] subu rd, rs, rt));
                     let x = self.regs.get::<u16>(rs);
                     let y = self.regs.get::<u16>(rt);
                     let diff: u16 = x.wrapping_sub(y);
@@ -286,66 +293,57 @@ impl Cpu {
                 OpcodeRegRegReg::XOR => unimplemented!(),
                 OpcodeRegRegReg::AND => unimplemented!(),
                 OpcodeRegRegReg::SHL => {
-                    self.log(log_instr!([size] shl rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] shl rd, rs, rt));
                     let value: u16 = self.regs.get::<u16>(rs) << self.regs.get::<u16>(rt);
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::SHR => {
-                    self.log(log_instr!([size] shr rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] shr rd, rs, rt));
                     let value: u16 = self.regs.get::<u16>(rs) >> self.regs.get::<u16>(rt);
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::SHRA => {
-                    self.log(log_instr!([size] shra rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] shra rd, rs, rt));
                     // Will perform sign-extension after shifting.
                     let value: i16 = self.regs.get::<i16>(rs) >> self.regs.get::<u16>(rt);
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::TLT => {
-                    self.log(log_instr!([size] tlt rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] tlt rd, rs, rt));
                     let value = self.regs.get::<i16>(rs) < self.regs.get(rt);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::TLTU => {
-                    self.log(log_instr!([size] tltu rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] tltu rd, rs, rt));
                     let value = self.regs.get::<u16>(rs) < self.regs.get(rt);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::TGE => {
-                    self.log(log_instr!([size] tge rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] tge rd, rs, rt));
                     let value = self.regs.get::<i16>(rs) >= self.regs.get(rt);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::TGEU => {
-                    self.log(log_instr!([size] tgeu rd, rs, rt));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] tgeu rd, rs, rt));
                     let value = self.regs.get::<u16>(rs) >= self.regs.get(rt);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::TEQ => {
-                    self.log(log_instr!([size] teq rd, rs, rt));
-                    self.breakpoint();
-                    let value = self.regs.get::<i16>(rs) == self.regs.get(rt);
+                    self.log(log_instr!([self.pc, size] teq rd, rs, rt));
+                    let value = self.regs.get::<i16>(rs) == self.regs.get::<i16>(rt);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
                 OpcodeRegRegReg::TNE => {
-                    self.log(log_instr!([size] tne rd, rs, rt));
-                    self.breakpoint();
-                    let value = self.regs.get::<u16>(rs) != self.regs.get(rt);
+                    self.log(log_instr!([self.pc, size] tne rd, rs, rt));
+                    let value = self.regs.get::<u16>(rs) != self.regs.get::<u16>(rt);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
@@ -359,20 +357,18 @@ impl Cpu {
             } => match opcode {
                 OpcodeRegRegImm::LW => {
                     let (rd, rs, addr_offset) = (reg1, reg2, imm10.as_i16());
-                    self.log(log_instr!([size] lw rd, addr_offset, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] lw rd, addr_offset, rs));
                     let addr_base = self.regs.get(rs);
-                    let value = self.mem_read_s16(addr_base, addr_offset);
+                    let value = self.load_s16(addr_base, addr_offset);
                     self.regs.set(rd, value);
                     self.pc += size;
                 }
                 OpcodeRegRegImm::LBS => unimplemented!(),
                 OpcodeRegRegImm::LBU => {
                     let (rd, rs, addr_offset) = (reg1, reg2, imm10.as_i16());
-                    self.log(log_instr!([size] lbu rd, addr_offset, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] lbu rd, addr_offset, rs));
                     let addr_base = self.regs.get(rs);
-                    let value = self.mem_read_u8(addr_base, addr_offset);
+                    let value = self.load_u8(addr_base, addr_offset);
                     self.regs.set(rd, value as u16);
                     self.pc += size;
                 }
@@ -385,34 +381,30 @@ impl Cpu {
                     //    base addr reg (rd)  |
                     //              value reg (rs)
                     let (rd, rs, addr_offset) = (reg1, reg2, imm10.as_i16());
-                    self.log(log_instr!([size] sw addr_offset, rd, rs ));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] sw addr_offset, rd, rs ));
                     let addr_base = self.regs.get(rd);
                     let value = self.regs.get(rs);
-                    self.mem_write_s16(addr_base, addr_offset, value);
+                    self.store_s16(addr_base, addr_offset, value);
                     self.pc += size;
                 }
                 OpcodeRegRegImm::SB => {
                     let (rd, rs, addr_offset) = (reg1, reg2, imm10.as_i16());
-                    self.log(log_instr!([size] sb addr_offset, rd, rs));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] sb addr_offset, rd, rs));
                     let addr_base = self.regs.get(rd);
                     let value = (self.regs.get::<u16>(rs) & 0x00FF) as u8;
-                    self.mem_write_u8(addr_base, addr_offset, value);
+                    self.store_u8(addr_base, addr_offset, value);
                     self.pc += size;
                 }
                 OpcodeRegRegImm::ADDI => {
                     let (rd, rs, simm) = (reg1, reg2, imm10.as_i16());
-                    self.log(log_instr!([size] addi rd, rs, simm));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] addi rd, rs, simm));
                     let sum: i16 = self.regs.get::<i16>(rs).wrapping_add(simm);
                     self.regs.set(rd, sum);
                     self.pc += size;
                 }
                 OpcodeRegRegImm::SUBI => {
                     let (rd, rs, simm) = (reg1, reg2, imm10.as_i16());
-                    self.log(log_instr!([size] subi rd, rs, simm));
-                    self.breakpoint();
+                    self.log(log_instr!([self.pc, size] subi rd, rs, simm));
                     let diff: i16 = self.regs.get::<i16>(rs).wrapping_sub(simm);
                     self.regs.set(rd, diff);
                     self.pc += size;
@@ -425,4 +417,18 @@ impl Cpu {
 
         Ok(())
     }
+
+    /// Pushes a frame onto the shadow call stack if `link_reg` follows the
+    /// calling convention (i.e. the return address is saved in `$ra`).
+    fn record_call(&mut self, link_reg: Reg, target: u16, size: u16) {
+        if link_reg == Reg::Ra {
+            let change = self.call_stack.push(CallFrame {
+                call_site: self.pc,
+                return_addr: self.pc + size,
+                callee: target,
+                sp: self.regs.get(Reg::Sp),
+            });
+            self.history.record_call_stack(change);
+        }
+    }
 }
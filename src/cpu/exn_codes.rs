@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 
+use super::interrupts::Interrupt;
 use super::regs::Reg;
-use super::{Cpu, LogMsg, Signal};
+use super::{Cpu, LogMsg, Signal, StepOutcome};
 
 mod codes {
     pub const ILLEGAL_INSTR: u16 = 0x0000;
@@ -11,39 +12,50 @@ mod codes {
 }
 
 impl Cpu {
-    pub fn handle_exn(&self, code: u16) {
+    pub fn handle_exn(&mut self, code: u16) {
+        if self.run_exn_handler(code) {
+            return;
+        }
+
         match code {
-            codes::ILLEGAL_INSTR => self.signal(Signal::IllegalInstr),
+            codes::ILLEGAL_INSTR => self.illegal_instr(),
 
             codes::DEBUG_BREAKPOINT => {
-                let lineno: u16 = self.regs.get(Reg::A0);
-                let _location = format!(
-                    "romfile: {}:{}",
-                    self.rom_src_path
-                        .as_ref()
-                        .map(|p| p.to_string_lossy())
-                        .unwrap_or_else(|| "<unknown>".into()),
-                    lineno
-                );
+                // Prefer the source map's location. Otherwise the assembler
+                // passes the line number in `$a0`.
+                let location = match self.src_loc(self.pc) {
+                    Some(loc) => loc.to_string(),
+                    None => {
+                        let lineno: u16 = self.regs.get(Reg::A0);
+                        format!(
+                            "{}:{}",
+                            self.rom_src_path
+                                .as_ref()
+                                .map(|p| p.to_string_lossy())
+                                .unwrap_or_else(|| "<unknown>".into()),
+                            lineno
+                        )
+                    }
+                };
+                self.log(LogMsg::BreakpointExn {
+                    pc: self.pc,
+                    location,
+                });
                 self.signal(Signal::Breakpoint)
-                // eprintln!("Breakpoint Exception: {location}");
-                // eprintln!("\t(at pc={})", self.pc);
-                // std::process::exit(0);
-                // TODO
             }
 
             codes::DIV_BY_ZERO => {
-                eprintln!("Division by zero at pc={}", self.pc);
-                todo!("handle division by zero");
+                self.log(LogMsg::Error(format!(
+                    "division by zero at pc=0x{:04X}",
+                    self.pc
+                )));
+                self.step_outcome = StepOutcome::Exception { code };
             }
 
             codes::DEBUG_PUTS => {
                 let s_ptr = self.regs.get(Reg::A0);
                 let s_len = self.regs.get(Reg::A1);
-                let s = (0..s_len)
-                    .map(|i| self.mem_read_u8(s_ptr, i))
-                    .map(char::from)
-                    .collect::<String>();
+                let s = self.read_str(s_ptr, s_len);
 
                 self.log(LogMsg::DebugPuts {
                     addr: s_ptr,
@@ -51,7 +63,52 @@ impl Cpu {
                 })
             }
 
-            other => unimplemented!("unimplemented exception code `0x{:X?}`", other),
+            // Other codes are left to the program: they trap to the
+            // `EXN_TRAP` handler once the `exn` has finished, with the code in
+            // `$cause`.
+            other if self.interrupt_handler(Interrupt::EXN_TRAP) != 0 => {
+                self.pending_trap = Some((Interrupt::EXN_TRAP, other));
+            }
+
+            other => {
+                self.log(LogMsg::Error(format!(
+                    "unhandled exception code `0x{other:X}` at pc=0x{:04X}",
+                    self.pc
+                )));
+                self.step_outcome = StepOutcome::Exception { code };
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{MemBlock, MemRw};
+
+    /// `exn 0x40` and `halt`, then a handler at 0x0803 which does
+    /// `mfsr $rv, $cause` and `kret`.
+    const ROM: &[u8] = &[0x00, 0x40, 0x04, 0x5C, 0x40, 0x00, 0x00, 0x34];
+
+    #[test]
+    fn unknown_exn_traps() {
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom);
+        cpu.mem
+            .write_s16(Interrupt::EXN_TRAP as u16, 0x0803u16.into());
+
+        assert_eq!(cpu.run(), StepOutcome::Halted);
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 0x40);
+        assert_eq!(cpu.cause, 0x40);
+        assert_eq!(cpu.pc, 0x0802);
+        assert_eq!(cpu.interrupt_return_address, 0x0802);
+        assert!(cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn unknown_exn_without_handler() {
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom);
+        assert_eq!(cpu.run(), StepOutcome::Exception { code: 0x40 });
+    }
+}
@@ -0,0 +1,305 @@
+//! A synchronous API for running the CPU as a library, for example from test
+//! harnesses, without channels or the interactive debugger.
+//!
+//! ```no_run
+//! use lark_vm::cpu::{Cpu, MemBlock, StepOutcome};
+//!
+//! let rom = MemBlock::from_vec(std::fs::read("prog.rom").unwrap()).unwrap();
+//! let mut cpu = Cpu::headless(rom);
+//! assert_eq!(cpu.run_for(10_000), StepOutcome::Halted);
+//! ```
+//!
+//! Log messages go to an [`Observer`], and everything else is reported by the
+//! [`StepOutcome`] of each step. When a step stops at a breakpoint, nothing is
+//! executed until [`Cpu::resume`] is called.
+//!
+//! [`Cpu::call`] calls a single function in the program, which is handy for
+//! unit-testing compiled code:
+//!
+//! ```no_run
+//! # use lark_vm::cpu::{symbols::SymbolTable, Cpu, MemBlock};
+//! # let rom = MemBlock::from_vec(std::fs::read("prog.rom").unwrap()).unwrap();
+//! let symbols = SymbolTable::load("prog.sym".as_ref()).unwrap();
+//! let add = symbols.addr_of("add").unwrap();
+//! let mut cpu = Cpu::headless(rom);
+//! assert_eq!(cpu.call(add, &[2, 3]), Ok(5));
+//! ```
+//!
+//! A `Cpu` is `Send`, so many can be run in parallel on worker threads.
+
+use std::{
+    fmt,
+    sync::{mpsc::Sender, Arc, Mutex},
+};
+
+use super::{interrupts::Interrupt, regs::Reg, Cpu, LogMsg, MemBlock, MemRw, Signal, ROM_SIZE};
+
+/// Receives the log messages and signals a CPU sends as it runs.
+pub trait Observer {
+    fn log(&mut self, msg: LogMsg);
+
+    /// Called for signals other than log messages. They are also reported as
+    /// the [`StepOutcome`], so they're ignored by default.
+    fn signal(&mut self, sig: Signal) {
+        if let Signal::Log(msg) = sig {
+            self.log(msg);
+        }
+    }
+}
+
+/// Forwards everything to a supervisor thread.
+impl Observer for Sender<Signal> {
+    fn log(&mut self, msg: LogMsg) {
+        Observer::signal(self, Signal::Log(msg));
+    }
+
+    fn signal(&mut self, sig: Signal) {
+        self.send(sig).unwrap();
+    }
+}
+
+/// Discards log messages.
+impl Observer for () {
+    fn log(&mut self, _msg: LogMsg) {}
+}
+
+/// What happened when the CPU took a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The instruction executed normally.
+    Continued,
+    /// The program executed `halt`.
+    Halted,
+    /// The CPU stopped at a breakpoint (without executing anything), or the
+    /// program raised a breakpoint exception.
+    Breakpoint,
+    /// The program raised an exception which neither the VM nor the program
+    /// handles.
+    Exception { code: u16 },
+    /// The program made a kernel call which no handler implements, or whose
+    /// handler failed.
+    KernelCall { number: u16 },
+    /// The instruction couldn't be decoded, or the program raised an illegal
+    /// instruction exception.
+    IllegalInstr,
+}
+
+/// The return address given to functions called by [`Cpu::call`]. The call
+/// is over when execution reaches it, so nothing here is ever executed.
+pub const CALL_RETURN_ADDR: u16 = 0xFFFE;
+
+/// The default maximum number of steps taken by [`Cpu::call`].
+pub const DEFAULT_CALL_BUDGET: u64 = 1_000_000;
+
+/// Why a function called by [`Cpu::call`] didn't return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// The program stopped before the function returned.
+    Stopped(StepOutcome),
+    /// The function didn't return within the step budget.
+    OutOfSteps,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stopped(outcome) => write!(f, "the call stopped early: {outcome:?}"),
+            Self::OutOfSteps => write!(f, "the call didn't return within its step budget"),
+        }
+    }
+}
+
+impl Cpu {
+    /// Creates a CPU which reports to nothing and receives interrupts only
+    /// from [`raise_interrupt`](Cpu::raise_interrupt). Breakpoints stop
+    /// [`step`](Cpu::step) instead of starting the interactive debugger.
+    pub fn headless(rom: MemBlock<ROM_SIZE>) -> Self {
+        let vtty = Arc::new(Mutex::new(MemBlock::new_zeroed()));
+        let mut cpu = Self::from_parts(rom, vtty, Box::new(()), None);
+        cpu.external_debugger = true;
+        cpu
+    }
+
+    pub fn with_observer(mut self, observer: impl Observer + Send + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    /// Sets the maximum number of steps taken by [`call`](Cpu::call).
+    pub fn with_call_budget(mut self, steps: u64) -> Self {
+        self.call_budget = steps;
+        self
+    }
+
+    /// Raises an interrupt, which the interrupt controller delivers before
+    /// the next instruction (once interrupts are enabled and nothing more
+    /// important is pending or in service).
+    pub fn raise_interrupt(&mut self, interrupt: Interrupt) {
+        self.mem.mmio.intc.raise(interrupt);
+    }
+
+    /// Leaves debug mode and executes the instruction at `pc`, even if it has
+    /// a breakpoint on it.
+    pub fn resume(&mut self) -> StepOutcome {
+        self.in_debug_mode = false;
+        self.watch_hit = None;
+        self.fetch();
+        self.execute_step()
+    }
+
+    /// Steps until `done` returns true (it's checked before each step) or a
+    /// step has an outcome other than [`StepOutcome::Continued`]. Returns the
+    /// last outcome.
+    pub fn run_until(&mut self, mut done: impl FnMut(&Cpu) -> bool) -> StepOutcome {
+        while !done(self) {
+            let outcome = self.step();
+            if outcome != StepOutcome::Continued {
+                return outcome;
+            }
+        }
+        StepOutcome::Continued
+    }
+
+    /// Takes up to `steps` steps, stopping early if one has an outcome other
+    /// than [`StepOutcome::Continued`]. Returns the last outcome.
+    pub fn run_for(&mut self, steps: u64) -> StepOutcome {
+        let mut taken = 0;
+        self.run_until(|_| {
+            taken += 1;
+            taken > steps
+        })
+    }
+
+    /// Calls the function at `addr` and returns its `$rv`.
+    ///
+    /// The first three arguments are passed in [`Reg::ARGUMENT`] and the rest
+    /// on the stack: the fourth at `[$sp]`, the fifth at `[$sp+2]`, and so on.
+    /// `$ra` is set to [`CALL_RETURN_ADDR`], and the call is over when the
+    /// function returns there. Afterwards the registers (other than `$rv`,
+    /// which holds the result if the function returned), `$lo`, `$hi`, `$pc`
+    /// and the shadow call stack are restored, so the CPU can carry on from
+    /// where it was. Memory written by the function isn't restored.
+    pub fn call(&mut self, addr: u16, args: &[u16]) -> Result<u16, CallError> {
+        let (regs, lo, hi) = (self.regs.clone(), self.lo, self.hi);
+        let (pc, call_stack) = (self.pc, self.call_stack.clone());
+        let sp = regs.get::<u16>(Reg::Sp);
+
+        for (&reg, &arg) in Reg::ARGUMENT.iter().zip(args) {
+            self.regs.set(reg, arg);
+        }
+        let stack_args = args.get(Reg::ARGUMENT.len()..).unwrap_or_default();
+        let args_sp = sp.wrapping_sub(2 * stack_args.len() as u16);
+        for (i, &arg) in stack_args.iter().enumerate() {
+            self.mem
+                .write_s16(args_sp.wrapping_add(2 * i as u16), arg.into());
+        }
+        self.regs.set(Reg::Sp, args_sp);
+        self.regs.set(Reg::Ra, CALL_RETURN_ADDR);
+        self.pc = addr;
+
+        let mut steps = 0;
+        let budget = self.call_budget;
+        let outcome = self.run_until(|cpu| {
+            steps += 1;
+            cpu.pc == CALL_RETURN_ADDR || steps > budget
+        });
+        let result = match outcome {
+            StepOutcome::Continued if self.pc == CALL_RETURN_ADDR => Ok(self.regs.get(Reg::Rv)),
+            StepOutcome::Continued => Err(CallError::OutOfSteps),
+            outcome => Err(CallError::Stopped(outcome)),
+        };
+
+        self.regs = regs;
+        if let Ok(value) = result {
+            self.regs.set(Reg::Rv, value);
+        }
+        (self.lo, self.hi) = (lo, hi);
+        self.pc = pc;
+        self.call_stack = call_stack;
+        self.fetch();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::cpu::regs::Reg;
+
+    /// `li $t0, 2`, then `exn 3` (debug puts) and `halt`.
+    const ROM: &[u8] = &[0x42, 0x40, 0x00, 0x80, 0x00, 0x03, 0x04];
+
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Observer for Collect {
+        fn log(&mut self, msg: LogMsg) {
+            if let LogMsg::Instr { name, .. } = msg {
+                self.0.lock().unwrap().push(name);
+            }
+        }
+    }
+
+    #[test]
+    fn run_headless() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom).with_observer(Collect(log.clone()));
+
+        assert_eq!(cpu.run_for(1), StepOutcome::Continued);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 2);
+
+        cpu.breakpoints.insert(0x0806);
+        assert_eq!(cpu.run(), StepOutcome::Breakpoint);
+        assert_eq!(cpu.pc, 0x0806);
+        assert_eq!(cpu.step(), StepOutcome::Breakpoint);
+        assert_eq!(cpu.resume(), StepOutcome::Halted);
+
+        assert_eq!(*log.lock().unwrap(), ["li", "exn", "halt"]);
+    }
+
+    #[test]
+    fn call_function() {
+        // `f(a, b, c, d, e) = a + b + c + d - e` at 0x0800, followed by an
+        // infinite loop at 0x0814.
+        let rom = MemBlock::from_vec(vec![
+            0x80, 0x4D, 0x00, 0x80, 0x45, 0x40, 0x46, 0x7C, 0x00, 0x80, 0x46, 0x40, 0x46, 0x7C,
+            0x02, 0x84, 0x46, 0x40, 0x24, 0x80, 0x20, 0x00, 0x00,
+        ])
+        .unwrap();
+        let mut cpu = Cpu::headless(rom).with_call_budget(100);
+        let sp = cpu.regs.get::<u16>(Reg::Sp);
+        cpu.regs.set(Reg::A0, 0x1234u16);
+        cpu.regs.set(Reg::T0, 0x5678u16);
+        cpu.regs.set(Reg::Rv, 9u16);
+
+        assert_eq!(cpu.call(0x0800, &[1, 2, 3, 40, 6]), Ok(40));
+        assert_eq!(cpu.regs.get::<u16>(Reg::Sp), sp);
+        assert_eq!(cpu.regs.get::<u16>(Reg::A0), 0x1234);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 0x5678);
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 40);
+        assert_eq!(cpu.pc, 0x0800);
+
+        assert_eq!(cpu.call(0x0814, &[]), Err(CallError::OutOfSteps));
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 40);
+        assert_eq!(cpu.call_stack.depth(), 0);
+    }
+
+    #[test]
+    fn run_on_worker_threads() {
+        // The CPUs are created here and moved to the workers.
+        let cpus: Vec<Cpu> = (0..64)
+            .map(|_| Cpu::headless(MemBlock::from_vec(ROM.to_vec()).unwrap()))
+            .collect();
+        let workers: Vec<_> = cpus
+            .into_iter()
+            .map(|mut cpu| std::thread::spawn(move || (cpu.run(), cpu)))
+            .collect();
+        for worker in workers {
+            let (outcome, cpu) = worker.join().unwrap();
+            assert_eq!(outcome, StepOutcome::Halted);
+            assert_eq!(cpu.regs.get::<u16>(Reg::T0), 2);
+        }
+    }
+}
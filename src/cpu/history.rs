@@ -0,0 +1,272 @@
+//! A bounded undo log of executed instructions which lets the debugger step
+//! backwards.
+//!
+//! Before each instruction executes, the CPU's registers are saved into a
+//! [`StepRecord`]. Every byte of memory the instruction overwrites has its old
+//! value appended to the record, as does any change to the shadow call stack.
+//! The interrupt controller is saved the first time the instruction changes
+//! it. Undoing a step restores the registers, the call stack and the
+//! interrupt controller, then the overwritten bytes in reverse order.
+//!
+//! Effects outside the CPU can't be undone: interrupts which were delivered
+//! aren't redelivered when execution is replayed, and MMIO device registers
+//! (other than the VTTY buffer and the interrupt controller) aren't restored.
+
+use std::collections::VecDeque;
+
+use crate::utils::s16;
+
+use super::{
+    call_stack::CallStackChange,
+    intc::{InterruptController, INTC_END, INTC_PENDING},
+    regs::RegisterFile,
+    Cpu, MemRw, Memory,
+};
+
+/// The state needed to undo a single instruction.
+#[derive(Clone)]
+pub struct StepRecord {
+    pub pc: u16,
+    pub ir: u32,
+    pub hi: s16,
+    pub lo: s16,
+    pub regs: RegisterFile,
+    pub interrupts_enabled: bool,
+    pub prev_interrupts_enabled: bool,
+    pub interrupt_return_address: u16,
+    pub cause: u16,
+    pub bad_addr: u16,
+    /// The interrupt controller, if the instruction changed it.
+    pub intc: Option<InterruptController>,
+    pub cycles: u64,
+    /// How the instruction changed the shadow call stack, if it did.
+    pub call_stack_change: Option<CallStackChange>,
+    /// The previous value of every byte written, in the order they were
+    /// written.
+    pub mem_writes: Vec<(u16, u8)>,
+    /// Did this instruction trigger a watchpoint?
+    pub watch_hit: bool,
+}
+
+pub struct History {
+    /// Maximum number of steps kept. Zero disables recording.
+    depth: usize,
+    /// Oldest step first.
+    records: VecDeque<StepRecord>,
+    /// The step currently being executed.
+    current: Option<StepRecord>,
+}
+
+impl History {
+    pub const DEFAULT_DEPTH: usize = 1024;
+
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            records: VecDeque::new(),
+            current: None,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Changes the maximum number of steps kept, forgetting the oldest steps
+    /// if necessary.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.records.len() > depth {
+            self.records.pop_front();
+        }
+    }
+
+    /// The number of steps which can currently be undone.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.current = None;
+    }
+
+    fn begin(&mut self, record: StepRecord) {
+        if self.depth > 0 {
+            self.current = Some(record);
+        }
+    }
+
+    fn record_write(&mut self, addr: u16, old: u8) {
+        if let Some(current) = &mut self.current {
+            current.mem_writes.push((addr, old));
+        }
+    }
+
+    pub(super) fn record_call_stack(&mut self, change: CallStackChange) {
+        if let Some(current) = &mut self.current {
+            current.call_stack_change = Some(change);
+        }
+    }
+
+    fn record_intc(&mut self, intc: &InterruptController) {
+        if let Some(current) = &mut self.current {
+            current.intc.get_or_insert_with(|| intc.clone());
+        }
+    }
+
+    pub(super) fn mark_watch_hit(&mut self) {
+        if let Some(current) = &mut self.current {
+            current.watch_hit = true;
+        }
+    }
+
+    fn commit(&mut self) {
+        if let Some(record) = self.current.take() {
+            if self.records.len() == self.depth {
+                self.records.pop_front();
+            }
+            self.records.push_back(record);
+        }
+    }
+
+    fn discard(&mut self) {
+        self.current = None;
+    }
+
+    fn pop(&mut self) -> Option<StepRecord> {
+        self.records.pop_back()
+    }
+}
+
+impl Cpu {
+    /// Starts recording the instruction about to be executed.
+    pub(super) fn begin_step_record(&mut self) {
+        if self.history.depth() == 0 {
+            return;
+        }
+
+        self.history.begin(StepRecord {
+            pc: self.pc,
+            ir: self.ir,
+            hi: self.hi,
+            lo: self.lo,
+            regs: self.regs.clone(),
+            interrupts_enabled: self.interrupts_enabled,
+            prev_interrupts_enabled: self.prev_interrupts_enabled,
+            interrupt_return_address: self.interrupt_return_address,
+            cause: self.cause,
+            bad_addr: self.bad_addr,
+            intc: None,
+            cycles: self.cycles(),
+            call_stack_change: None,
+            mem_writes: Vec::new(),
+            watch_hit: false,
+        });
+    }
+
+    /// Finishes recording the current instruction. If it failed to execute
+    /// there is nothing to undo, so the record is dropped.
+    pub(super) fn end_step_record(&mut self, executed: bool) {
+        if executed {
+            self.history.commit();
+        } else {
+            self.history.discard();
+        }
+    }
+
+    /// Saves the bytes about to be overwritten by an instruction's store of
+    /// `len` bytes at `addr`.
+    pub(super) fn record_mem_write(&mut self, addr: u16, len: u16) {
+        for i in 0..len {
+            let addr = addr.wrapping_add(i);
+            if (INTC_PENDING..=INTC_END).contains(&addr) {
+                self.record_intc();
+            }
+            if Memory::is_device_register(addr) {
+                continue;
+            }
+            let old = self.mem.read_u8(addr);
+            self.history.record_write(addr, old);
+        }
+    }
+
+    /// Saves the interrupt controller before the current instruction changes
+    /// it.
+    pub(super) fn record_intc(&mut self) {
+        self.history.record_intc(&self.mem.mmio.intc);
+    }
+
+    /// Undoes the most recently executed instruction. Returns the undone
+    /// record, or `None` if there is no more history.
+    pub fn reverse_step(&mut self) -> Option<StepRecord> {
+        let record = self.history.pop()?;
+
+        self.pc = record.pc;
+        self.ir = record.ir;
+        self.hi = record.hi;
+        self.lo = record.lo;
+        self.regs = record.regs.clone();
+        self.interrupts_enabled = record.interrupts_enabled;
+        self.prev_interrupts_enabled = record.prev_interrupts_enabled;
+        self.interrupt_return_address = record.interrupt_return_address;
+        self.cause = record.cause;
+        self.bad_addr = record.bad_addr;
+        if let Some(intc) = &record.intc {
+            self.mem.mmio.intc = intc.clone();
+        }
+        self.mem.mmio.cycles = record.cycles;
+        if let Some(change) = &record.call_stack_change {
+            self.call_stack.undo(change);
+        }
+        for &(addr, old) in record.mem_writes.iter().rev() {
+            self.mem.write_u8(addr, old);
+        }
+
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{regs::Reg, MemBlock, StepOutcome};
+
+    /// `li $t1, 0x1800`, `li $t0, 0x1234`, `sw 0($t1), $t0`, `jal $ra, f` and
+    /// `halt`, then `f` at 0x0810: `li $t0, 7` and `jr $ra`.
+    const ROM: &[u8] = &[
+        0x42, 0x86, 0x00, 0x00, 0x42, 0x44, 0x8D, 0x00, 0x56, 0xA4, 0x00, 0x28, 0x80, 0x01, 0x40,
+        0x04, 0x42, 0x40, 0x01, 0xC0, 0x24, 0x80,
+    ];
+
+    #[test]
+    fn reverse_step_restores_state() {
+        let mut cpu = Cpu::headless(MemBlock::from_vec(ROM.to_vec()).unwrap());
+        assert_eq!(cpu.run_for(6), StepOutcome::Continued);
+        assert_eq!(cpu.pc, 0x080F);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 7);
+        assert_eq!(cpu.mem.read_s16(0x1800).as_u16(), 0x1234);
+        assert_eq!(cpu.call_stack.depth(), 0);
+
+        // Undo `jr $ra` and `li $t0, 7`.
+        cpu.reverse_step().unwrap();
+        assert_eq!((cpu.pc, cpu.call_stack.depth()), (0x0814, 1));
+        cpu.reverse_step().unwrap();
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 0x1234);
+
+        // Undo `jal` and `sw`.
+        cpu.reverse_step().unwrap();
+        assert_eq!((cpu.pc, cpu.call_stack.depth()), (0x080B, 0));
+        cpu.reverse_step().unwrap();
+        assert_eq!(cpu.mem.read_s16(0x1800).as_u16(), 0);
+        assert_eq!(cpu.history.len(), 2);
+
+        // Replaying gets back to the same place.
+        assert_eq!(cpu.run(), StepOutcome::Halted);
+        assert_eq!(cpu.mem.read_s16(0x1800).as_u16(), 0x1234);
+    }
+}
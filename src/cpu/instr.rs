@@ -1,6 +1,6 @@
 use core::fmt;
 
-use super::regs::Reg;
+use super::{interrupts::SYSTEM_REGS, regs::Reg, symbols::SymbolTable};
 use crate::utils::s16;
 
 /// You probably want to `use ops::*;` since theres a lot of these.
@@ -159,6 +159,10 @@ pub mod ops {
         BF = opcodes::BF,
         /// Load Immediate
         LI = opcodes::LI,
+        /// Move From Special Register (`$status`, `$cause`, `$epc` or `$badaddr`)
+        MFSR = opcodes::MFSR,
+        /// Move To Special Register
+        MTSR = opcodes::MTSR,
     }
 
     impl fmt::Display for OpcodeRegImm {
@@ -168,6 +172,8 @@ pub mod ops {
                 Self::BT => "bt",
                 Self::BF => "bf",
                 Self::LI => "li",
+                Self::MFSR => "mfsr",
+                Self::MTSR => "mtsr",
             };
             write!(f, "{}", name)
         }
@@ -182,6 +188,8 @@ pub mod ops {
                 "bt" => Ok(Self::BT),
                 "bf" => Ok(Self::BF),
                 "li" => Ok(Self::LI),
+                "mfsr" => Ok(Self::MFSR),
+                "mtsr" => Ok(Self::MTSR),
                 _ => Err(()),
             }
         }
@@ -487,3 +495,80 @@ impl<R: fmt::Display, Imm: fmt::Display> fmt::Display for Instr<R, Imm> {
         }
     }
 }
+
+impl<R, Imm> Instr<R, Imm> {
+    /// The instruction's opcode.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instr::O { opcode } => *opcode as u8,
+            Instr::A { opcode, .. } => *opcode as u8,
+            Instr::I { opcode, .. } => *opcode as u8,
+            Instr::R { opcode, .. } => *opcode as u8,
+            Instr::RI { opcode, .. } => *opcode as u8,
+            Instr::RR { opcode, .. } => *opcode as u8,
+            Instr::RRR { opcode, .. } => *opcode as u8,
+            Instr::RRI { opcode, .. } => *opcode as u8,
+        }
+    }
+
+    /// The instruction's mnemonic, like `addi`.
+    pub fn opcode_name(&self) -> String {
+        match self {
+            Instr::O { opcode } => opcode.to_string(),
+            Instr::A { opcode, .. } => opcode.to_string(),
+            Instr::I { opcode, .. } => opcode.to_string(),
+            Instr::R { opcode, .. } => opcode.to_string(),
+            Instr::RI { opcode, .. } => opcode.to_string(),
+            Instr::RR { opcode, .. } => opcode.to_string(),
+            Instr::RRR { opcode, .. } => opcode.to_string(),
+            Instr::RRI { opcode, .. } => opcode.to_string(),
+        }
+    }
+}
+
+impl Instr {
+    /// Displays the instruction as if it were located at address `pc`. Jump
+    /// and branch targets are shown as absolute addresses, resolved to labels
+    /// where possible, instead of as raw offsets.
+    pub fn display_at<'a>(&'a self, pc: u16, symbols: &'a SymbolTable) -> InstrAt<'a> {
+        InstrAt {
+            instr: self,
+            pc,
+            symbols,
+        }
+    }
+}
+
+/// See [`Instr::display_at`].
+pub struct InstrAt<'a> {
+    instr: &'a Instr,
+    pc: u16,
+    symbols: &'a SymbolTable,
+}
+
+impl fmt::Display for InstrAt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = |offset: &s16| {
+            self.symbols
+                .fmt_addr(self.pc.wrapping_add_signed(offset.as_i16()))
+        };
+
+        match self.instr {
+            Instr::A { opcode, offset } => write!(f, "{opcode}\t{}", target(offset)),
+            Instr::RI {
+                opcode: opcode @ (OpcodeRegImm::JAL | OpcodeRegImm::BT | OpcodeRegImm::BF),
+                reg,
+                imm,
+            } => write!(f, "{opcode}\t{reg}, {}", target(imm)),
+            Instr::RI {
+                opcode: opcode @ (OpcodeRegImm::MFSR | OpcodeRegImm::MTSR),
+                reg,
+                imm,
+            } => match SYSTEM_REGS.get(imm.as_u16() as usize) {
+                Some(spr) => write!(f, "{opcode}\t{reg}, ${spr}"),
+                None => write!(f, "{}", self.instr),
+            },
+            instr => write!(f, "{instr}"),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! The interrupt controller, which decides which raised interrupt is taken
+//! next.
+//!
+//! Each [`Interrupt`] is a source with a fixed priority, given by its position
+//! in [`Interrupt::BY_PRIORITY`]: bit 0 of the controller's registers is the
+//! highest priority source. A raised interrupt stays pending until it's taken
+//! or acknowledged, so nothing is lost while interrupts are disabled.
+//!
+//! Before each instruction, if interrupts are enabled, the CPU takes the
+//! highest priority interrupt which is pending, enabled in the mask, and of
+//! higher priority than every interrupt currently being serviced. An interrupt
+//! is in service from when it's taken until its handler returns with `kret`,
+//! so a handler which re-enables interrupts with `inre` can only be preempted
+//! by more important ones.
+//!
+//! The guest sees the controller as four MMIO words:
+//!
+//! | Address             | Register    | Access                                 |
+//! |---------------------|-------------|----------------------------------------|
+//! | [`INTC_PENDING`]    | pending     | read, or write 1s to raise those bits  |
+//! | [`INTC_MASK`]       | mask        | read/write (all enabled at reset)      |
+//! | [`INTC_ACK`]        | acknowledge | write 1s to clear those pending bits   |
+//! | [`INTC_IN_SERVICE`] | in service  | read                                   |
+//!
+//! The acknowledge register reads as zero, and writes to the in-service
+//! register are ignored. Each byte can also be accessed on its own: writing
+//! one byte of the mask leaves the other alone, while writing one byte of the
+//! pending or acknowledge register affects only the bits in that byte.
+//!
+//! Writing to the pending register is a software interrupt: the program can
+//! trigger any vector, which is taken like one raised by a device. It's taken
+//! before the next instruction (if nothing prevents it), so `$epc` holds the
+//! address of the instruction after the store.
+
+use super::{interrupts::Interrupt, Cpu};
+
+/// Address of the pending interrupts register.
+pub const INTC_PENDING: u16 = 0x0018;
+/// Address of the interrupt mask register.
+pub const INTC_MASK: u16 = 0x001A;
+/// Address of the acknowledge register.
+pub const INTC_ACK: u16 = 0x001C;
+/// Address of the in-service register.
+pub const INTC_IN_SERVICE: u16 = 0x001E;
+/// Address of the last byte of the interrupt controller's MMIO registers.
+pub const INTC_END: u16 = INTC_IN_SERVICE + 1;
+
+/// The most interrupts tracked as in service at once. Handlers which never
+/// `kret` would otherwise make the list grow forever.
+const MAX_NESTING: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct InterruptController {
+    pub pending: u16,
+    pub mask: u16,
+    /// Interrupts which have been taken but whose handlers haven't returned,
+    /// innermost last.
+    pub in_service: Vec<Interrupt>,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self {
+            pending: 0,
+            mask: u16::MAX,
+            in_service: Vec::new(),
+        }
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn raise(&mut self, interrupt: Interrupt) {
+        self.pending |= interrupt.bit();
+    }
+
+    /// Raises the interrupts whose bits are set in `bits`. Bits which don't
+    /// belong to an interrupt are ignored.
+    pub fn raise_bits(&mut self, bits: u16) {
+        self.pending |= bits & ((1 << Interrupt::BY_PRIORITY.len()) - 1);
+    }
+
+    /// Clears the pending bits which are set in `bits`.
+    pub fn acknowledge(&mut self, bits: u16) {
+        self.pending &= !bits;
+    }
+
+    /// The bits of the interrupts in service.
+    pub fn in_service_bits(&self) -> u16 {
+        self.in_service.iter().fold(0, |bits, i| bits | i.bit())
+    }
+
+    /// The interrupt which should be taken next, if any.
+    pub fn next(&self) -> Option<Interrupt> {
+        let in_service = self.in_service_bits();
+        // Only bits below the highest priority one in service can preempt.
+        let allowed = match in_service.trailing_zeros() {
+            16 => u16::MAX,
+            n => (1 << n) - 1,
+        };
+        let ready = self.pending & self.mask & allowed;
+        Interrupt::BY_PRIORITY
+            .into_iter()
+            .find(|i| ready & i.bit() != 0)
+    }
+
+    /// Notes that `interrupt` has been taken.
+    pub fn begin_service(&mut self, interrupt: Interrupt) {
+        self.pending &= !interrupt.bit();
+        if self.in_service.len() == MAX_NESTING {
+            self.in_service.remove(0);
+        }
+        self.in_service.push(interrupt);
+    }
+
+    /// Notes that the innermost interrupt's handler has returned.
+    pub fn end_service(&mut self) {
+        self.in_service.pop();
+    }
+
+    /// Reads the register containing `addr`.
+    pub fn read(&self, addr: u16) -> u16 {
+        match addr & !1 {
+            INTC_PENDING => self.pending,
+            INTC_MASK => self.mask,
+            INTC_IN_SERVICE => self.in_service_bits(),
+            _ => 0,
+        }
+    }
+
+    /// Writes the register containing `addr`.
+    pub fn write(&mut self, addr: u16, value: u16) {
+        match addr & !1 {
+            INTC_PENDING => self.raise_bits(value),
+            INTC_MASK => self.mask = value,
+            INTC_ACK => self.acknowledge(value),
+            _ => {}
+        }
+    }
+
+    /// Writes the byte at `addr`, which is the most significant byte of its
+    /// register if `addr` is even.
+    pub fn write_u8(&mut self, addr: u16, value: u8) {
+        let shift = if addr & 1 == 0 { 8 } else { 0 };
+        let bits = (value as u16) << shift;
+        match addr & !1 {
+            INTC_MASK => self.mask = (self.mask & !(0xFF << shift)) | bits,
+            // Zeros in the other byte raise or acknowledge nothing.
+            reg => self.write(reg, bits),
+        }
+    }
+}
+
+impl Cpu {
+    /// Moves interrupts raised by other threads into the controller, then
+    /// takes the next one if interrupts are enabled.
+    pub(super) fn poll_interrupts(&mut self) {
+        if let Some(channel) = &self.pending_interrupts {
+            for interrupt in channel.try_iter() {
+                self.mem.mmio.intc.raise(interrupt);
+            }
+        }
+
+        if !self.interrupts_enabled {
+            return;
+        }
+        if let Some(interrupt) = self.mem.mmio.intc.next() {
+            self.send_interrupt(interrupt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{regs::Reg, MemBlock, MemRw, StepOutcome};
+
+    #[test]
+    fn priority_and_nesting() {
+        let mut intc = InterruptController::new();
+        intc.raise(Interrupt::KEY_EVENT);
+        intc.raise(Interrupt::TIMER_EXP);
+        assert_eq!(intc.next(), Some(Interrupt::TIMER_EXP));
+        intc.begin_service(Interrupt::TIMER_EXP);
+
+        // The key event can't preempt the timer's handler, but an illegal
+        // instruction can.
+        assert_eq!(intc.next(), None);
+        intc.raise(Interrupt::ILL_INSTR);
+        assert_eq!(intc.next(), Some(Interrupt::ILL_INSTR));
+        intc.begin_service(Interrupt::ILL_INSTR);
+        intc.end_service();
+        intc.end_service();
+        assert_eq!(intc.next(), Some(Interrupt::KEY_EVENT));
+
+        intc.write(INTC_MASK, !Interrupt::KEY_EVENT.bit());
+        assert_eq!(intc.next(), None);
+        assert_eq!(intc.read(INTC_PENDING), Interrupt::KEY_EVENT.bit());
+        intc.write(INTC_ACK, Interrupt::KEY_EVENT.bit());
+        assert_eq!(intc.read(INTC_PENDING), 0);
+    }
+
+    #[test]
+    fn software_interrupt() {
+        // `li $t0, 0x10` and `sw 0x18($zero), $t0` raise `KEY_EVENT`, then
+        // `halt`. The handler at 0x0808 is `li $rv, 7` and `kret`.
+        let rom = MemBlock::from_vec(vec![
+            0x42, 0x40, 0x04, 0x00, 0x54, 0x24, 0x18, 0x04, 0x40, 0x40, 0x01, 0xC0, 0x34,
+        ])
+        .unwrap();
+        let mut cpu = Cpu::headless(rom);
+        cpu.mem
+            .write_s16(Interrupt::KEY_EVENT as u16, 0x0808u16.into());
+
+        assert_eq!(cpu.run_for(100), StepOutcome::Halted);
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 7);
+        assert_eq!(cpu.cause, Interrupt::KEY_EVENT as u16);
+        assert_eq!(cpu.interrupt_return_address, 0x0807);
+
+        let mut intc = InterruptController::new();
+        intc.write(INTC_PENDING, 0xFFFF);
+        assert_eq!(intc.read(INTC_PENDING), 0x001F);
+    }
+
+    #[test]
+    fn byte_and_unaligned_access() {
+        let mut intc = InterruptController::new();
+        intc.write_u8(INTC_MASK + 1, 0x0F);
+        assert_eq!(intc.mask, 0xFF0F);
+        intc.write_u8(INTC_PENDING + 1, 0x03);
+        intc.write_u8(INTC_PENDING, 0xFF);
+        assert_eq!(intc.pending, 0x0003);
+        intc.write_u8(INTC_ACK + 1, 0x01);
+        assert_eq!(intc.pending, 0x0002);
+        assert_eq!(intc.read(INTC_ACK), 0);
+        intc.write(INTC_IN_SERVICE, 0xFFFF);
+        assert_eq!(intc.read(INTC_IN_SERVICE + 1), 0);
+
+        // `li $t0, 0x0F`, `sb 0x1B($zero), $t0`, `lw $t1, 0x19($zero)`,
+        // `sb 0x1F($zero), $t0`, `sw 0x1F($zero), $t0`, `lw $t2, 0x1F($zero)`
+        // and `halt`.
+        let rom = MemBlock::from_vec(vec![
+            0x42, 0x40, 0x03, 0xC0, 0x58, 0x24, 0x1B, 0x46, 0x80, 0x19, 0x58, 0x24, 0x1F, 0x54,
+            0x24, 0x1F, 0x46, 0xC0, 0x1F, 0x04,
+        ])
+        .unwrap();
+        let mut cpu = Cpu::headless(rom);
+        cpu.regs.set(Reg::T2, 0xFFFFu16);
+
+        assert_eq!(cpu.run_for(100), StepOutcome::Halted);
+        assert_eq!(cpu.mem.mmio.intc.mask, 0xFF0F);
+        // The low byte of pending, then the high byte of the mask.
+        assert_eq!(cpu.regs.get::<u16>(Reg::T1), 0x00FF);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T2), 0);
+        assert_eq!(cpu.mem.mmio.intc.pending, 0);
+    }
+}
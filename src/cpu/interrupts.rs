@@ -1,9 +1,32 @@
-use crate::cpu::{Cpu, MemRw};
+//! Interrupts and the registers that describe them. Which interrupt is taken
+//! when is up to the [interrupt controller](super::intc).
+//!
+//! Taking an interrupt saves `$pc` in `$epc`, records why in `$cause`, saves
+//! and clears the interrupt-enable bit of `$status`, then jumps to the handler
+//! whose address is stored in the interrupt's vector. `kret` undoes this,
+//! returning to `$epc` and restoring the interrupt-enable bit.
+//!
+//! `$cause` holds the vector address of the interrupt, except for
+//! [`Interrupt::EXN_TRAP`], where it holds the exception code. Exception codes
+//! are only 10 bits, so they never collide with a vector address.
+//!
+//! `exn 0`, words which don't decode to an instruction, and `mfsr`/`mtsr` with
+//! an invalid register all take [`Interrupt::ILL_INSTR`] with the faulting
+//! instruction's address in `$badaddr`. They are taken even when interrupts
+//! are disabled. After `exn 0`, `$epc` is the next instruction; otherwise the
+//! instruction didn't execute and `$epc` points at it, so a handler which
+//! wants to skip it must advance `$epc` itself.
+//!
+//! The handler can read and write these registers with `mfsr` and `mtsr`. A
+//! handler which wants to allow nested interrupts saves `$epc` and `$status`
+//! before re-enabling interrupts with `inre`, and restores them before `kret`.
 
-use super::regs::Reg;
+use crate::cpu::{Cpu, MemRw, Signal};
+
+use super::debugger::Spr;
 
 /// These are addresses in memory where function *pointers* are stored.
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 #[expect(non_camel_case_types)]
 pub enum Interrupt {
@@ -11,17 +34,149 @@ pub enum Interrupt {
     DIV_ZERO = 0xFFFC,  // Division by Zero
     KEY_EVENT = 0xFFFA, // Keyboard Event
     TIMER_EXP = 0xFFF8, // Timer Expiration
+    EXN_TRAP = 0xFFF6,  // Exception code not handled by the VM
+}
+
+impl Interrupt {
+    /// Every interrupt, highest priority first.
+    pub const BY_PRIORITY: [Interrupt; 5] = [
+        Interrupt::ILL_INSTR,
+        Interrupt::DIV_ZERO,
+        Interrupt::EXN_TRAP,
+        Interrupt::TIMER_EXP,
+        Interrupt::KEY_EVENT,
+    ];
+
+    /// The interrupt's bit in the interrupt controller's registers.
+    pub fn bit(self) -> u16 {
+        let i = Self::BY_PRIORITY.iter().position(|&i| i == self).unwrap();
+        1 << i
+    }
 }
 
+/// `$status` bit: interrupts are enabled.
+pub const STATUS_IE: u16 = 1 << 0;
+/// `$status` bit: interrupts were enabled before the current one was taken.
+pub const STATUS_PIE: u16 = 1 << 1;
+
+/// The special-purpose registers accessible to `mfsr` and `mtsr`, by number.
+pub const SYSTEM_REGS: [Spr; 4] = [Spr::Cause, Spr::Status, Spr::Epc, Spr::BadAddr];
+
 impl Cpu {
     pub fn send_interrupt(&mut self, interrupt: Interrupt) {
-        // Disable interrupts.
+        self.trap(interrupt, interrupt as u16);
+    }
+
+    /// Takes `interrupt`, with `cause` in `$cause`.
+    pub(super) fn trap(&mut self, interrupt: Interrupt, cause: u16) {
+        self.record_intc();
+        self.prev_interrupts_enabled = self.interrupts_enabled;
         self.interrupts_enabled = false;
-        // Save the current PC to the K0 register.
-        self.regs.set(Reg::K0, self.pc);
+        self.interrupt_return_address = self.pc;
+        self.cause = cause;
+        self.mem.mmio.intc.begin_service(interrupt);
 
         // Jump to the interrupt handler.
-        let handler_address = self.mem.read_s16(interrupt as u16).as_u16();
-        self.pc = handler_address;
+        self.pc = self.interrupt_handler(interrupt);
+    }
+
+    /// Reports an illegal instruction at `$pc`, which is saved in `$badaddr`.
+    /// If the program has a handler for [`Interrupt::ILL_INSTR`], it's taken
+    /// once the instruction has finished. Otherwise the CPU stops.
+    pub(super) fn illegal_instr(&mut self) {
+        self.bad_addr = self.pc;
+        if self.interrupt_handler(Interrupt::ILL_INSTR) != 0 {
+            self.pending_trap = Some((Interrupt::ILL_INSTR, Interrupt::ILL_INSTR as u16));
+        } else {
+            self.signal(Signal::IllegalInstr);
+        }
+    }
+
+    /// Returns from an interrupt handler.
+    pub(super) fn kret(&mut self) {
+        self.record_intc();
+        self.interrupts_enabled = self.prev_interrupts_enabled;
+        self.pc = self.interrupt_return_address;
+        self.mem.mmio.intc.end_service();
+    }
+
+    /// The address of the handler installed for `interrupt`, or zero if
+    /// there isn't one.
+    pub fn interrupt_handler(&self, interrupt: Interrupt) -> u16 {
+        self.mem.read_s16(interrupt as u16).as_u16()
+    }
+
+    /// The value of `$status`.
+    pub fn status(&self) -> u16 {
+        let mut status = 0;
+        if self.interrupts_enabled {
+            status |= STATUS_IE;
+        }
+        if self.prev_interrupts_enabled {
+            status |= STATUS_PIE;
+        }
+        status
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.interrupts_enabled = status & STATUS_IE != 0;
+        self.prev_interrupts_enabled = status & STATUS_PIE != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{regs::Reg, MemBlock, StepOutcome};
+
+    /// A word which doesn't decode at 0x0800, then `halt`. The handler at
+    /// 0x0805 skips the word and returns its address in `$rv`.
+    const ROM: &[u8] = &[
+        0x0C, 0x00, 0x00, 0x00, 0x04, 0x5E, 0x40, 0x00, 0x80, 0xA2, 0x64, 0x04, 0x62, 0x40, 0x00,
+        0x80, 0x5C, 0x40, 0x00, 0xC0, 0x34,
+    ];
+
+    #[test]
+    fn undecodable_instr_traps() {
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom);
+        cpu.mem
+            .write_s16(Interrupt::ILL_INSTR as u16, 0x0805u16.into());
+        cpu.interrupts_enabled = false;
+
+        assert_eq!(cpu.run_for(100), StepOutcome::Halted);
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 0x0800);
+        assert_eq!(cpu.pc, 0x0804);
+
+        // Without a handler, the CPU stops instead of retrying forever.
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom);
+        assert_eq!(cpu.run_for(100), StepOutcome::IllegalInstr);
+        assert_eq!(cpu.pc, 0x0800);
+    }
+
+    #[test]
+    fn invalid_system_register_faults() {
+        // `mfsr $t0, 9`, `mtsr $t0, 9` and `halt`. The handler at 0x0809
+        // copies `$badaddr` to `$rv`, counts the fault in `$s0` and returns
+        // past the faulting instruction.
+        let rom = MemBlock::from_vec(vec![
+            0x5E, 0x40, 0x02, 0x40, 0x62, 0x40, 0x02, 0x40, 0x04, 0x5C, 0x40, 0x00, 0xC0, 0x5F,
+            0x00, 0x00, 0x80, 0xA3, 0x30, 0x04, 0x63, 0x00, 0x00, 0x80, 0xA1, 0x98, 0x01, 0x34,
+        ])
+        .unwrap();
+        let mut cpu = Cpu::headless(rom);
+        cpu.mem
+            .write_s16(Interrupt::ILL_INSTR as u16, 0x0809u16.into());
+
+        assert_eq!(cpu.run_for(100), StepOutcome::Halted);
+        assert_eq!(cpu.regs.get::<u16>(Reg::S0), 2);
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 0x0804);
+
+        let rom = MemBlock::from_vec(vec![0x5E, 0x40, 0x02, 0x40, 0x04]).unwrap();
+        let mut cpu = Cpu::headless(rom);
+        assert_eq!(cpu.run_for(100), StepOutcome::IllegalInstr);
+        assert_eq!(cpu.pc, 0x0800);
+        assert_eq!(cpu.bad_addr, 0x0800);
     }
 }
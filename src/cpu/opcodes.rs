@@ -18,6 +18,8 @@ pub const LBU: u8 = 0x13;
 pub const MV: u8 = 0x14;
 pub const SW: u8 = 0x15;
 pub const SB: u8 = 0x16;
+pub const MFSR: u8 = 0x17;
+pub const MTSR: u8 = 0x18;
 pub const INRE: u8 = 0x1C;
 pub const INRD: u8 = 0x1D;
 pub const ADD: u8 = 0x20;
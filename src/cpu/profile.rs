@@ -0,0 +1,340 @@
+//! An instruction-level profiler, enabled with `--profile`.
+//!
+//! Every executed instruction is counted by address and by opcode. When the
+//! program halts, a report lists the hottest instructions, the time spent in
+//! each function (the nearest label at or before each address, when a symbol
+//! table is loaded) and the opcode mix.
+//!
+//! The profiler also attributes each instruction to the calls active when it
+//! executed, using the shadow [`CallStack`]. This gives inclusive and exclusive
+//! counts per function, and can be written in the folded-stack format read by
+//! flamegraph tools: one line per distinct stack, like `main;f;g 12`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+use bitvec::prelude::*;
+
+use super::{call_stack::CallStack, instr::Instr, symbols::SymbolTable, Cpu};
+
+/// Number of rows in the hot-spot table.
+const HOT_SPOTS: usize = 20;
+
+#[derive(Default)]
+pub struct Profile {
+    /// Total number of instructions executed.
+    pub total: u64,
+    /// Execution counts and the (most recent) instruction at each address.
+    pub by_pc: HashMap<u16, (u64, u32)>,
+    /// Execution counts by mnemonic.
+    pub by_opcode: HashMap<String, u64>,
+    /// Execution counts by call stack. See [`Profile::enter`] for the format.
+    pub by_stack: HashMap<Vec<u16>, u64>,
+    /// The call stack of the instruction being executed.
+    stack: Vec<u16>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notes the call stack of the instruction at `pc`, which is about to be
+    /// executed. The stack is stored as an address in the outermost function
+    /// (the first call site, or `pc` if there are no calls) followed by the
+    /// entry point of each called function.
+    pub fn enter(&mut self, pc: u16, call_stack: &CallStack) {
+        self.stack.clear();
+        let frames = call_stack.frames();
+        match frames.front() {
+            None => self.stack.push(pc),
+            Some(outermost) => {
+                self.stack.push(outermost.call_site);
+                self.stack.extend(frames.iter().map(|frame| frame.callee));
+            }
+        }
+    }
+
+    /// Counts an execution of the instruction `ir` at `pc`, with the call
+    /// stack given to the last [`enter`](Profile::enter).
+    pub fn record(&mut self, pc: u16, ir: u32) {
+        self.total += 1;
+        match self.by_stack.get_mut(&self.stack[..]) {
+            Some(count) => *count += 1,
+            None => {
+                self.by_stack.insert(self.stack.clone(), 1);
+            }
+        }
+        let entry = self.by_pc.entry(pc).or_insert((0, ir));
+        entry.0 += 1;
+        entry.1 = ir;
+        let name = match Instr::from_bits(ir.view_bits::<Msb0>()) {
+            Ok(instr) => instr.opcode_name(),
+            Err(_) => "???".to_string(),
+        };
+        *self.by_opcode.entry(name).or_default() += 1;
+    }
+
+    /// Execution counts summed by the function each address is in.
+    pub fn by_function(&self, symbols: &SymbolTable) -> HashMap<String, u64> {
+        let mut counts = HashMap::new();
+        for (&pc, &(count, _)) in &self.by_pc {
+            let name = match symbols.lookup(pc) {
+                Some((name, _offset)) => name.to_string(),
+                None => "(unknown)".to_string(),
+            };
+            *counts.entry(name).or_default() += count;
+        }
+        counts
+    }
+
+    /// Execution counts by the names of the functions on the call stack,
+    /// outermost first.
+    pub fn folded_stacks(&self, symbols: &SymbolTable) -> Vec<(Vec<String>, u64)> {
+        let mut counts: HashMap<Vec<String>, u64> = HashMap::new();
+        for (stack, &count) in &self.by_stack {
+            let root = match symbols.lookup(stack[0]) {
+                Some((name, _offset)) => name.to_string(),
+                None => "(root)".to_string(),
+            };
+            let names = std::iter::once(root)
+                .chain(stack[1..].iter().map(|&callee| symbols.fmt_addr(callee)))
+                .collect();
+            *counts.entry(names).or_default() += count;
+        }
+        let mut stacks: Vec<_> = counts.into_iter().collect();
+        stacks.sort();
+        stacks
+    }
+
+    /// Writes the call stacks in the folded-stack format.
+    pub fn write_folded_stacks(&self, path: &Path, symbols: &SymbolTable) -> Result<(), String> {
+        let mut out = String::new();
+        for (names, count) in self.folded_stacks(symbols) {
+            writeln!(out, "{} {count}", names.join(";")).unwrap();
+        }
+        std::fs::write(path, out).map_err(|e| format!("could not write `{}`: {e}", path.display()))
+    }
+
+    /// Inclusive and exclusive execution counts of every function on a call
+    /// stack. Recursive calls are only counted once towards the inclusive
+    /// count.
+    pub fn call_graph(&self, symbols: &SymbolTable) -> HashMap<String, (u64, u64)> {
+        let mut counts: HashMap<String, (u64, u64)> = HashMap::new();
+        for (names, count) in self.folded_stacks(symbols) {
+            let mut seen = HashSet::new();
+            for name in &names {
+                if seen.insert(name) {
+                    counts.entry(name.clone()).or_default().0 += count;
+                }
+            }
+            counts.entry(names.last().unwrap().clone()).or_default().1 += count;
+        }
+        counts
+    }
+
+    /// Formats the hot-spot report.
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        let pct = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+
+        writeln!(out, "Profile: {} instructions executed", self.total).unwrap();
+
+        let mut hot: Vec<_> = self.by_pc.iter().collect();
+        hot.sort_by(|(pc_a, (count_a, _)), (pc_b, (count_b, _))| {
+            count_b.cmp(count_a).then(pc_a.cmp(pc_b))
+        });
+        writeln!(out, "\nHot spots:").unwrap();
+        writeln!(
+            out,
+            "{:>10} {:>7}  {:<20} instruction",
+            "count", "%", "address"
+        )
+        .unwrap();
+        for (&pc, &(count, ir)) in hot.iter().take(HOT_SPOTS) {
+            let instr = match Instr::from_bits(ir.view_bits::<Msb0>()) {
+                Ok(instr) => instr.display_at(pc, symbols).to_string(),
+                Err(_) => format!("0x{ir:08X}"),
+            };
+            writeln!(
+                out,
+                "{count:>10} {:>6.2}%  {:<20} {}",
+                pct(count),
+                symbols.fmt_addr(pc),
+                instr.replace('\t', " "),
+            )
+            .unwrap();
+        }
+        if hot.len() > HOT_SPOTS {
+            writeln!(
+                out,
+                "{:>10}  ({} more addresses)",
+                "...",
+                hot.len() - HOT_SPOTS
+            )
+            .unwrap();
+        }
+
+        let mut sections = vec![("opcode", self.by_opcode.clone())];
+        if !symbols.is_empty() {
+            sections.insert(0, ("function", self.by_function(symbols)));
+        }
+        for (title, counts) in sections {
+            let mut rows: Vec<_> = counts.into_iter().collect();
+            rows.sort_by(|(name_a, count_a), (name_b, count_b)| {
+                count_b.cmp(count_a).then(name_a.cmp(name_b))
+            });
+            writeln!(out, "\nBy {title}:").unwrap();
+            writeln!(out, "{:>10} {:>7}  {title}", "count", "%").unwrap();
+            for (name, count) in rows {
+                writeln!(out, "{count:>10} {:>6.2}%  {name}", pct(count)).unwrap();
+            }
+        }
+
+        let mut rows: Vec<_> = self.call_graph(symbols).into_iter().collect();
+        rows.sort_by(|(name_a, (incl_a, _)), (name_b, (incl_b, _))| {
+            incl_b.cmp(incl_a).then(name_a.cmp(name_b))
+        });
+        writeln!(out, "\nCall graph:").unwrap();
+        writeln!(
+            out,
+            "{:>10} {:>7} {:>10} {:>7}  function",
+            "inclusive", "%", "exclusive", "%"
+        )
+        .unwrap();
+        for (name, (inclusive, exclusive)) in rows {
+            writeln!(
+                out,
+                "{inclusive:>10} {:>6.2}% {exclusive:>10} {:>6.2}%  {name}",
+                pct(inclusive),
+                pct(exclusive),
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+impl Cpu {
+    pub fn with_profile(mut self, profile: bool) -> Self {
+        self.profile = profile.then(Profile::new);
+        self
+    }
+
+    /// Writes the profile's call stacks in the folded-stack format, if
+    /// profiling.
+    pub fn write_folded_stacks(&self, path: &Path) -> Result<(), String> {
+        match &self.profile {
+            Some(profile) => profile.write_folded_stacks(path, &self.symbols),
+            None => Ok(()),
+        }
+    }
+
+    /// Prints the profiler's report to stderr, if profiling.
+    pub fn print_profile(&self) {
+        if let Some(profile) = &self.profile {
+            eprint!("{}", profile.report(&self.symbols));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::call_stack::CallFrame;
+
+    #[test]
+    fn call_graph_counts() {
+        let symbols = SymbolTable::parse("main = 0x0800\nf = 0x0810\n").unwrap();
+        let mut call_stack = CallStack::new();
+        let mut profile = Profile::new();
+        let nop = 0x08248000;
+
+        profile.enter(0x0800, &call_stack);
+        profile.record(0x0800, nop);
+        // `main` calls `f`, which calls itself.
+        for call_site in [0x0804, 0x0814] {
+            call_stack.push(CallFrame {
+                call_site,
+                return_addr: call_site + 4,
+                callee: 0x0810,
+                sp: 0,
+            });
+            profile.enter(0x0810, &call_stack);
+            profile.record(0x0810, nop);
+        }
+
+        let folded = profile.folded_stacks(&symbols);
+        assert_eq!(
+            folded,
+            vec![
+                (vec!["main".to_string()], 1),
+                (vec!["main".to_string(), "f".to_string()], 1),
+                (
+                    vec!["main".to_string(), "f".to_string(), "f".to_string()],
+                    1
+                ),
+            ]
+        );
+        let graph = profile.call_graph(&symbols);
+        assert_eq!(graph["main"], (3, 1));
+        assert_eq!(graph["f"], (2, 2));
+    }
+
+    #[test]
+    fn report_counts() {
+        let symbols = SymbolTable::parse("main = 0x0800\nf = 0x0810\n").unwrap();
+        let call_stack = CallStack::new();
+        let mut profile = Profile::new();
+        let li = 0x42448D00;
+        let nop = 0x08248000;
+        let halt = 0x04000000;
+
+        for (pc, ir, count) in [
+            (0x07F0, halt, 1),
+            (0x0800, li, 3),
+            (0x0810, nop, 5),
+            (0x0812, nop, 2),
+        ] {
+            for _ in 0..count {
+                profile.enter(pc, &call_stack);
+                profile.record(pc, ir);
+            }
+        }
+
+        assert_eq!(profile.total, 11);
+        assert_eq!(profile.by_opcode["li"], 3);
+        assert_eq!(profile.by_opcode["nop"], 7);
+        assert_eq!(profile.by_opcode["halt"], 1);
+        let by_function = profile.by_function(&symbols);
+        assert_eq!(by_function["main"], 3);
+        assert_eq!(by_function["f"], 7);
+        assert_eq!(by_function["(unknown)"], 1);
+
+        let report = profile.report(&symbols);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[4..8],
+            [
+                "         5  45.45%  f                    nop",
+                "         3  27.27%  main                 li $t0, 0x1234s16",
+                "         2  18.18%  f+0x2                nop",
+                "         1   9.09%  0x07F0               halt",
+            ]
+        );
+        assert_eq!(
+            lines[9..14],
+            [
+                "By function:",
+                "     count       %  function",
+                "         7  63.64%  f",
+                "         3  27.27%  main",
+                "         1   9.09%  (unknown)",
+            ]
+        );
+    }
+}
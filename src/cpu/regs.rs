@@ -178,6 +178,7 @@ impl TryFrom<u8> for Reg {
         }
     }
 }
+#[derive(Clone)]
 pub struct RegisterFile {
     indexed: [s16; 15],
 }
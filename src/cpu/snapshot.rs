@@ -0,0 +1,263 @@
+//! Saving and restoring the complete state of a machine, so that a program
+//! can be resumed later from where it was checkpointed.
+//!
+//! A snapshot file starts with the magic bytes `LARKSNAP` and a version
+//! number, followed by the CPU registers (including the interrupt registers
+//! `$epc`, `$status`, `$cause` and `$badaddr`), the cycle counter, the
+//! interrupt controller's state, the breakpoints, and the contents of every
+//! memory segment (including the VTTY buffer). Multi-byte values are
+//! big-endian, like Lark memory.
+//!
+//! The debugger's history and shadow call stack aren't saved, so they start
+//! out empty after a restore.
+
+use std::{collections::BTreeSet, path::Path};
+
+use crate::utils::s16;
+
+use super::{
+    intc::InterruptController,
+    interrupts::Interrupt,
+    regs::{Reg, RegisterFile},
+    Cpu, MemBlock, KERNEL_MEM_SIZE, ROM_SIZE, USER_MEM_SIZE, VTTY_BYTES,
+};
+
+const MAGIC: &[u8; 8] = b"LARKSNAP";
+const VERSION: u16 = 5;
+
+/// The number of registers in a [`RegisterFile`] (all but `$zero`).
+const NUM_REGS: u8 = 15;
+
+impl Cpu {
+    /// Serializes the machine's state.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_be_bytes());
+
+        out.extend_from_slice(&self.pc.to_be_bytes());
+        out.extend_from_slice(&self.ir.to_be_bytes());
+        out.extend_from_slice(&self.hi.as_u16().to_be_bytes());
+        out.extend_from_slice(&self.lo.as_u16().to_be_bytes());
+        for (_reg, value) in self.regs.iter() {
+            out.extend_from_slice(&value.as_u16().to_be_bytes());
+        }
+        out.extend_from_slice(&self.interrupt_return_address.to_be_bytes());
+        out.extend_from_slice(&self.status().to_be_bytes());
+        out.extend_from_slice(&self.cause.to_be_bytes());
+        out.extend_from_slice(&self.bad_addr.to_be_bytes());
+        out.extend_from_slice(&self.cycles().to_be_bytes());
+
+        let intc = &self.mem.mmio.intc;
+        out.extend_from_slice(&intc.pending.to_be_bytes());
+        out.extend_from_slice(&intc.mask.to_be_bytes());
+        out.extend_from_slice(&(intc.in_service.len() as u16).to_be_bytes());
+        for &interrupt in &intc.in_service {
+            out.extend_from_slice(&(interrupt as u16).to_be_bytes());
+        }
+
+        out.extend_from_slice(&(self.breakpoints.len() as u16).to_be_bytes());
+        for addr in &self.breakpoints {
+            out.extend_from_slice(&addr.to_be_bytes());
+        }
+
+        out.extend_from_slice(&self.mem.rom.mem[..]);
+        out.extend_from_slice(&self.mem.user.mem[..]);
+        out.extend_from_slice(&self.mem.kernel.mem[..]);
+        out.extend_from_slice(&self.mem.mmio.vtty_buf.lock().unwrap().mem[..]);
+
+        out
+    }
+
+    /// Replaces the machine's state with a snapshot made by
+    /// [`snapshot`](Cpu::snapshot). On error the machine is left unchanged.
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut r = Reader { bytes };
+
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err("not a lark-vm snapshot".into());
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(format!(
+                "unsupported snapshot version {version} (expected {VERSION})"
+            ));
+        }
+
+        let pc = r.u16()?;
+        let ir = r.u32()?;
+        let hi = r.u16()?;
+        let lo = r.u16()?;
+        let mut regs = RegisterFile::new(0);
+        for i in 1..=NUM_REGS {
+            regs.set(Reg::try_from(i).unwrap(), r.u16()?);
+        }
+        let interrupt_return_address = r.u16()?;
+        let status = r.u16()?;
+        let cause = r.u16()?;
+        let bad_addr = r.u16()?;
+        let cycles = r.u64()?;
+
+        let mut intc = InterruptController::new();
+        intc.pending = r.u16()?;
+        intc.mask = r.u16()?;
+        for _ in 0..r.u16()? {
+            let vector = r.u16()?;
+            let interrupt = Interrupt::BY_PRIORITY
+                .into_iter()
+                .find(|&i| i as u16 == vector)
+                .ok_or_else(|| format!("invalid interrupt vector 0x{vector:04X}"))?;
+            intc.in_service.push(interrupt);
+        }
+
+        let mut breakpoints = BTreeSet::new();
+        for _ in 0..r.u16()? {
+            breakpoints.insert(r.u16()?);
+        }
+
+        let rom = MemBlock::<ROM_SIZE>::from_vec(r.bytes(ROM_SIZE)?.to_vec()).unwrap();
+        let user = MemBlock::<USER_MEM_SIZE>::from_vec(r.bytes(USER_MEM_SIZE)?.to_vec()).unwrap();
+        let kernel =
+            MemBlock::<KERNEL_MEM_SIZE>::from_vec(r.bytes(KERNEL_MEM_SIZE)?.to_vec()).unwrap();
+        let vtty = r.bytes(VTTY_BYTES)?;
+
+        if !r.bytes.is_empty() {
+            return Err("unexpected data at the end of the snapshot".into());
+        }
+
+        self.pc = pc;
+        self.ir = ir;
+        self.hi = s16::from(hi);
+        self.lo = s16::from(lo);
+        self.regs = regs;
+        self.interrupt_return_address = interrupt_return_address;
+        self.set_status(status);
+        self.cause = cause;
+        self.bad_addr = bad_addr;
+        self.mem.mmio.cycles = cycles;
+        self.mem.mmio.intc = intc;
+        self.breakpoints = breakpoints;
+        self.mem.rom = rom;
+        self.mem.user = user;
+        self.mem.kernel = kernel;
+        self.mem
+            .mmio
+            .vtty_buf
+            .lock()
+            .unwrap()
+            .mem
+            .copy_from_slice(vtty);
+
+        self.step_from_line = None;
+        self.watch_hit = None;
+        self.call_stack.clear();
+        self.history.clear();
+
+        Ok(())
+    }
+
+    /// Saves a snapshot of the machine to a file.
+    pub fn save_snapshot(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.snapshot())
+            .map_err(|e| format!("could not write snapshot `{}`: {e}", path.display()))
+    }
+
+    /// Restores the machine from a snapshot file.
+    pub fn load_snapshot(&mut self, path: &Path) -> Result<(), String> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("could not read snapshot `{}`: {e}", path.display()))?;
+        self.restore(&bytes)
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("snapshot is truncated".into());
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use super::*;
+    use crate::cpu::{MemRw, Memory, VTTY_START};
+
+    fn new_cpu() -> Cpu {
+        let (logger_tx, _logger_rx) = mpsc::channel();
+        let (_interrupt_tx, interrupt_rx) = mpsc::channel();
+        let rom = MemBlock::from_vec(vec![0xAB; 16]).unwrap();
+        let vtty = Arc::new(Mutex::new(MemBlock::new_zeroed()));
+        Cpu::new(rom, vtty, logger_tx, interrupt_rx)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cpu = new_cpu();
+        cpu.pc = 0x0812;
+        cpu.ir = 0xDEADBEEF;
+        cpu.lo = s16::from(7u16);
+        cpu.regs.set(Reg::T0, 0x1234u16);
+        cpu.interrupts_enabled = false;
+        cpu.prev_interrupts_enabled = true;
+        cpu.cause = 0x0042;
+        cpu.mem.mmio.intc.raise(Interrupt::KEY_EVENT);
+        cpu.mem.mmio.intc.begin_service(Interrupt::TIMER_EXP);
+        cpu.mem.mmio.cycles = 1234;
+        cpu.breakpoints.insert(0x0820);
+        cpu.mem.write_u8(Memory::USER_START + 3, 0x42);
+        cpu.mem.write_u8(VTTY_START, b'!');
+        let snapshot = cpu.snapshot();
+
+        let mut restored = new_cpu();
+        restored.restore(&snapshot).unwrap();
+
+        assert_eq!(restored.pc, 0x0812);
+        assert_eq!(restored.ir, 0xDEADBEEF);
+        assert_eq!(restored.lo.as_u16(), 7);
+        assert_eq!(restored.regs.get::<u16>(Reg::T0), 0x1234);
+        assert!(!restored.interrupts_enabled);
+        assert!(restored.prev_interrupts_enabled);
+        assert_eq!(restored.cause, 0x0042);
+        assert_eq!(restored.mem.mmio.intc.pending, Interrupt::KEY_EVENT.bit());
+        assert_eq!(restored.mem.mmio.intc.in_service, [Interrupt::TIMER_EXP]);
+        assert_eq!(restored.cycles(), 1234);
+        assert!(restored.breakpoints.contains(&0x0820));
+        assert_eq!(restored.mem.read_u8(Memory::USER_START + 3), 0x42);
+        assert_eq!(restored.mem.read_u8(VTTY_START), b'!');
+        assert_eq!(restored.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn rejects_bad_snapshots() {
+        let mut cpu = new_cpu();
+        let mut snapshot = cpu.snapshot();
+
+        assert!(cpu.restore(&snapshot[..snapshot.len() - 1]).is_err());
+        snapshot[MAGIC.len() + 1] = 99;
+        assert!(cpu.restore(&snapshot).is_err());
+        assert!(cpu.restore(b"not a snapshot").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Source maps relate ROM addresses to the lines of the source files (lark
+//! assembly or meadowlark) they were generated from.
+//!
+//! A source map is a plain text sidecar file emitted by the assembler or
+//! compiler. Each line holds one address range and the source location it came
+//! from:
+//!
+//! ```text
+//! ; start  end     location
+//! 0x0800   0x0804  examples/ill-instr.lark.asm:5
+//! 0x0804   0x0808  examples/ill-instr.lark.asm:6
+//! ```
+//!
+//! The `end` address is exclusive. Blank lines and lines beginning with `;`
+//! are ignored. Relative file paths are relative to the source map's own
+//! directory.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrcLoc {
+    pub file: PathBuf,
+    pub line: u32,
+}
+
+impl fmt::Display for SrcLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SrcMapEntry {
+    start: u16,
+    end: u16,
+    loc: SrcLoc,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Sorted by start address.
+    entries: Vec<SrcMapEntry>,
+}
+
+impl SourceMap {
+    /// The source map conventionally stored next to a ROM file, with a
+    /// `.srcmap` extension. Returns `None` if there isn't one.
+    pub fn default_path(romfile: &Path) -> Option<PathBuf> {
+        let path = romfile.with_extension("srcmap");
+        path.exists().then_some(path)
+    }
+
+    /// Reads and parses a source map file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read source map `{}`: {e}", path.display()))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, base_dir).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Parses the text of a source map. Relative file paths are joined onto
+    /// `base_dir`.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Self, String> {
+        let mut entries = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let lineno = i + 1;
+            let mut fields = line.split_whitespace();
+            let (Some(start), Some(end), Some(loc), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(format!("line {lineno}: expected `START END FILE:LINE`"));
+            };
+
+            let start = parse_addr(start)
+                .ok_or_else(|| format!("line {lineno}: invalid start address `{start}`"))?;
+            let end = parse_addr(end)
+                .ok_or_else(|| format!("line {lineno}: invalid end address `{end}`"))?;
+            if end < start {
+                return Err(format!(
+                    "line {lineno}: address range ends before it starts"
+                ));
+            }
+
+            let Some((file, src_line)) = loc.rsplit_once(':') else {
+                return Err(format!("line {lineno}: expected `FILE:LINE`, got `{loc}`"));
+            };
+            let src_line = src_line
+                .parse()
+                .map_err(|_| format!("line {lineno}: invalid line number `{src_line}`"))?;
+
+            entries.push(SrcMapEntry {
+                start,
+                end,
+                loc: SrcLoc {
+                    file: base_dir.join(file),
+                    line: src_line,
+                },
+            });
+        }
+
+        entries.sort_by_key(|e| e.start);
+        Ok(Self { entries })
+    }
+
+    /// Returns the source location which generated the code at `addr`.
+    pub fn lookup(&self, addr: u16) -> Option<&SrcLoc> {
+        let i = self.entries.partition_point(|e| e.start <= addr);
+        let entry = &self.entries[i.checked_sub(1)?];
+        (addr < entry.end).then_some(&entry.loc)
+    }
+
+    /// Every address range in the map and the source location which
+    /// generated it, in address order. Range ends are exclusive.
+    pub fn ranges(&self) -> impl Iterator<Item = (u16, u16, &SrcLoc)> {
+        self.entries.iter().map(|e| (e.start, e.end, &e.loc))
+    }
+
+    /// Finds the first address generated by the given source line. If no code
+    /// was generated for that line, the next line in the same file which has
+    /// code is used instead.
+    ///
+    /// `file` matches any path in the source map which ends with it, so
+    /// `main.lark.asm` matches `examples/main.lark.asm`.
+    pub fn resolve_line(&self, file: &Path, line: u32) -> Option<(u16, &SrcLoc)> {
+        self.entries
+            .iter()
+            .filter(|e| e.loc.file.ends_with(file) && e.loc.line >= line)
+            .min_by_key(|e| (e.loc.line, e.start))
+            .map(|e| (e.start, &e.loc))
+    }
+}
+
+fn parse_addr(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_MAP: &str = "\
+; start  end     location
+0x0800   0x0804  main.lark.asm:5
+
+0x0805   0x0807  main.lark.asm:9
+0x0804   0x0805  main.lark.asm:6
+";
+
+    #[test]
+    fn lookup_and_resolve() {
+        let map = SourceMap::parse(SRC_MAP, Path::new("examples")).unwrap();
+        let loc = |line| SrcLoc {
+            file: PathBuf::from("examples/main.lark.asm"),
+            line,
+        };
+
+        assert_eq!(map.lookup(0x07FF), None);
+        assert_eq!(map.lookup(0x0800), Some(&loc(5)));
+        assert_eq!(map.lookup(0x0803), Some(&loc(5)));
+        assert_eq!(map.lookup(0x0804), Some(&loc(6)));
+        assert_eq!(map.lookup(0x0806), Some(&loc(9)));
+        assert_eq!(map.lookup(0x0807), None);
+
+        let file = Path::new("main.lark.asm");
+        assert_eq!(map.resolve_line(file, 6), Some((0x0804, &loc(6))));
+        assert_eq!(map.resolve_line(file, 7), Some((0x0805, &loc(9))));
+        assert_eq!(map.resolve_line(file, 10), None);
+        assert_eq!(map.resolve_line(Path::new("other.lark"), 5), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(SourceMap::parse("0x0800 0x0804", Path::new("")).is_err());
+        assert!(SourceMap::parse("0x0800 0x0804 main.lark", Path::new("")).is_err());
+        assert!(SourceMap::parse("0x0804 0x0800 main.lark:1", Path::new("")).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Symbol tables map the labels of a program (like `main` or
+//! `handle__ILL_INSTR`) to the addresses they were assembled to.
+//!
+//! A symbol file is a plain text sidecar file with one label per line:
+//!
+//! ```text
+//! ; label          address
+//! main = 0x0808
+//! handle__ILL_INSTR = 0x0818
+//! ```
+//!
+//! The `=` is optional. Blank lines and lines beginning with `;` are ignored.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    by_addr: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The symbol file conventionally stored next to a ROM file, with a `.sym`
+    /// extension. Returns `None` if there isn't one.
+    pub fn default_path(romfile: &Path) -> Option<PathBuf> {
+        let path = romfile.with_extension("sym");
+        path.exists().then_some(path)
+    }
+
+    /// Reads and parses a symbol file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read symbol file `{}`: {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let lineno = i + 1;
+            let fields = line
+                .split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|field| !field.is_empty())
+                .collect::<Vec<_>>();
+            let [name, addr] = fields[..] else {
+                return Err(format!("line {lineno}: expected `LABEL = ADDRESS`"));
+            };
+
+            let addr = match addr.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                None => addr.parse().ok(),
+            }
+            .ok_or_else(|| format!("line {lineno}: invalid address `{addr}`"))?;
+
+            symbols.insert(name.to_string(), addr);
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, name: String, addr: u16) {
+        // If several labels share an address keep the first for display.
+        self.by_addr.entry(addr).or_insert_with(|| name.clone());
+        let Some(old_addr) = self.by_name.insert(name.clone(), addr) else {
+            return;
+        };
+
+        // The name has been rebound, so it no longer labels its old address.
+        // Another label there (if any) takes over.
+        if old_addr != addr && self.by_addr.get(&old_addr) == Some(&name) {
+            let other = self
+                .by_name
+                .iter()
+                .find(|&(_, &a)| a == old_addr)
+                .map(|(other, _)| other.clone());
+            match other {
+                Some(other) => self.by_addr.insert(old_addr, other),
+                None => self.by_addr.remove(&old_addr),
+            };
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Returns the address of the label `name`.
+    pub fn addr_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Returns the nearest label at or before `addr` and the offset of `addr`
+    /// from it.
+    pub fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+        let (&sym_addr, name) = self.by_addr.range(..=addr).next_back()?;
+        Some((name, addr - sym_addr))
+    }
+
+    /// Formats `addr` relative to the nearest label, like `main+0x6`. Returns
+    /// `None` if there is no label at or before `addr`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        match self.lookup(addr)? {
+            (name, 0) => Some(name.to_string()),
+            (name, offset) => Some(format!("{name}+0x{offset:X}")),
+        }
+    }
+
+    /// Formats `addr` symbolically if possible, and in hex otherwise.
+    pub fn fmt_addr(&self, addr: u16) -> String {
+        self.describe(addr)
+            .unwrap_or_else(|| format!("0x{addr:04X}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_lookup() {
+        let symbols = SymbolTable::parse(
+            "; comment\n\
+             main = 0x0808\n\
+             boot 0x0800\n\
+             handle__ILL_INSTR = 2072\n",
+        )
+        .unwrap();
+
+        assert_eq!(symbols.addr_of("main"), Some(0x0808));
+        assert_eq!(symbols.addr_of("handle__ILL_INSTR"), Some(0x0818));
+        assert_eq!(symbols.addr_of("nope"), None);
+
+        assert_eq!(symbols.fmt_addr(0x07FF), "0x07FF");
+        assert_eq!(symbols.fmt_addr(0x0800), "boot");
+        assert_eq!(symbols.fmt_addr(0x0806), "boot+0x6");
+        assert_eq!(symbols.fmt_addr(0x080E), "main+0x6");
+
+        assert!(SymbolTable::parse("main").is_err());
+        assert!(SymbolTable::parse("main = xyz").is_err());
+    }
+
+    #[test]
+    fn redefine_symbol() {
+        let mut symbols = SymbolTable::parse("loop = 0x0804\nagain = 0x0806").unwrap();
+        symbols.insert("loop".into(), 0x0810);
+        assert_eq!(symbols.addr_of("loop"), Some(0x0810));
+        assert_eq!(symbols.fmt_addr(0x0804), "0x0804");
+        assert_eq!(symbols.fmt_addr(0x0812), "loop+0x2");
+
+        // A label sharing the old address takes over from the rebound one.
+        symbols.insert("top".into(), 0x0806);
+        symbols.insert("again".into(), 0x0900);
+        assert_eq!(symbols.fmt_addr(0x0806), "top");
+    }
+}
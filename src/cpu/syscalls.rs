@@ -0,0 +1,152 @@
+//! Exception and kernel call handlers implemented by the host.
+//!
+//! An embedder can register a Rust closure for an `exn` or `kcall` number, for
+//! example to give programs file I/O or to let tests make assertions. The
+//! handler runs in place of the instruction's usual effect, with full access
+//! to the CPU's registers and memory. `$pc` still points at the `exn`/`kcall`
+//! instruction while it runs, and execution continues after it.
+//!
+//! Handlers take priority over the built-in exception codes, so they can also
+//! replace defaults like `DEBUG_PUTS`. If a handler returns an error, it's
+//! logged and the step's outcome is [`StepOutcome::Exception`] (or
+//! [`StepOutcome::KernelCall`] for a kernel call, which is also the outcome of
+//! a kernel call without a handler).
+//!
+//! ```no_run
+//! # use lark_vm::cpu::{regs::Reg, Cpu, MemBlock};
+//! # let rom = MemBlock::from_vec(std::fs::read("prog.rom").unwrap()).unwrap();
+//! let cpu = Cpu::headless(rom).with_exn_handler(0x20, |cpu| {
+//!     let (a, b): (u16, u16) = (cpu.regs.get(Reg::A0), cpu.regs.get(Reg::A1));
+//!     if a != b {
+//!         return Err(format!("assertion failed: {a} != {b}"));
+//!     }
+//!     Ok(())
+//! });
+//! ```
+
+use std::collections::HashMap;
+
+use super::{Cpu, LogMsg, StepOutcome};
+
+/// A host-implemented exception or kernel call.
+pub type Syscall = Box<dyn FnMut(&mut Cpu) -> Result<(), String> + Send>;
+
+#[derive(Default)]
+pub struct Syscalls {
+    /// Handlers for `exn` codes.
+    pub exns: HashMap<u16, Syscall>,
+    /// Handlers for `kcall` numbers.
+    pub kcalls: HashMap<u16, Syscall>,
+}
+
+impl Syscalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Cpu {
+    pub fn with_exn_handler(
+        mut self,
+        code: u16,
+        handler: impl FnMut(&mut Cpu) -> Result<(), String> + Send + 'static,
+    ) -> Self {
+        self.syscalls.exns.insert(code, Box::new(handler));
+        self
+    }
+
+    pub fn with_kcall_handler(
+        mut self,
+        number: u16,
+        handler: impl FnMut(&mut Cpu) -> Result<(), String> + Send + 'static,
+    ) -> Self {
+        self.syscalls.kcalls.insert(number, Box::new(handler));
+        self
+    }
+
+    /// Runs the handler registered for `exn code`, if there is one. Returns
+    /// whether there was.
+    pub(super) fn run_exn_handler(&mut self, code: u16) -> bool {
+        let Some(mut handler) = self.syscalls.exns.remove(&code) else {
+            return false;
+        };
+        let result = handler(self);
+        self.syscalls.exns.entry(code).or_insert(handler);
+        self.syscall_result(result, StepOutcome::Exception { code });
+        true
+    }
+
+    pub(super) fn handle_kcall(&mut self, number: u16) {
+        let Some(mut handler) = self.syscalls.kcalls.remove(&number) else {
+            self.log(LogMsg::Error(format!(
+                "unimplemented kernel call `0x{number:X}` at pc=0x{:04X}",
+                self.pc
+            )));
+            self.step_outcome = StepOutcome::KernelCall { number };
+            return;
+        };
+        let result = handler(self);
+        self.syscalls.kcalls.entry(number).or_insert(handler);
+        self.syscall_result(result, StepOutcome::KernelCall { number });
+    }
+
+    /// Logs a handler's error, stopping with `outcome`.
+    fn syscall_result(&mut self, result: Result<(), String>, outcome: StepOutcome) {
+        if let Err(e) = result {
+            self.log(LogMsg::Error(format!("{e} (at pc=0x{:04X})", self.pc)));
+            self.step_outcome = outcome;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::cpu::{regs::Reg, MemBlock};
+
+    /// `li $a0, 5`, `li $a1, 7`, `exn 0x20`, `kcall 1` and `halt`.
+    const ROM: &[u8] = &[
+        0x40, 0xC0, 0x01, 0x40, 0x41, 0x00, 0x01, 0xC0, 0x00, 0x20, 0x38, 0x01, 0x04,
+    ];
+
+    #[test]
+    fn host_syscalls() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let log = calls.clone();
+        let mut cpu = Cpu::headless(rom)
+            .with_exn_handler(0x20, |cpu| {
+                let (a, b): (u16, u16) = (cpu.regs.get(Reg::A0), cpu.regs.get(Reg::A1));
+                cpu.regs.set(Reg::Rv, a + b);
+                Ok(())
+            })
+            .with_kcall_handler(1, move |cpu| {
+                log.lock().unwrap().push(cpu.pc);
+                Ok(())
+            });
+
+        assert_eq!(cpu.run(), StepOutcome::Halted);
+        assert_eq!(cpu.regs.get::<u16>(Reg::Rv), 12);
+        assert_eq!(*calls.lock().unwrap(), [0x080A]);
+
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom).with_exn_handler(0x20, |_| Err("nope".into()));
+        assert_eq!(cpu.run(), StepOutcome::Exception { code: 0x20 });
+    }
+
+    #[test]
+    fn unhandled_kcall() {
+        // Without a handler for `kcall 1`.
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom).with_exn_handler(0x20, |_| Ok(()));
+        assert_eq!(cpu.run(), StepOutcome::KernelCall { number: 1 });
+
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom)
+            .with_exn_handler(0x20, |_| Ok(()))
+            .with_kcall_handler(1, |_| Err("nope".into()));
+        assert_eq!(cpu.run(), StepOutcome::KernelCall { number: 1 });
+    }
+}
@@ -0,0 +1,208 @@
+//! The timing model, which counts how many clock cycles a program has taken.
+//!
+//! Each instruction costs a number of cycles given by a [`CostTable`], plus
+//! extra cycles for each memory access it makes, plus wait states if that
+//! access is to an MMIO device register. The running total is readable by the
+//! host with [`Cpu::cycles`] and by the guest from the read-only MMIO register
+//! at [`CYCLES_ADDR`]: four words holding the 64-bit count, most significant
+//! word first.
+//!
+//! A cost table file overrides the defaults. Each line holds a mnemonic (or
+//! `mem` for the memory access cost, or `mmio` for the wait states) and a
+//! number of cycles:
+//!
+//! ```text
+//! ; name  cycles
+//! mul     3
+//! div     10
+//! mem     1
+//! mmio    4
+//! ```
+
+use std::path::Path;
+
+use super::{
+    instr::{ops::*, Instr},
+    Cpu, Memory,
+};
+
+/// Address of the cycle counter's MMIO register.
+pub const CYCLES_ADDR: u16 = 0x0010;
+/// Address of the last byte of the cycle counter's MMIO register.
+pub const CYCLES_END: u16 = CYCLES_ADDR + 7;
+
+/// Number of distinct opcodes.
+const NUM_OPCODES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct CostTable {
+    /// Cycles taken by each instruction, indexed by opcode.
+    instrs: [u64; NUM_OPCODES],
+    /// Extra cycles taken by each load or store.
+    pub mem_access: u64,
+    /// Extra cycles taken by each load or store to an MMIO device register.
+    pub mmio_wait: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        let mut table = Self {
+            instrs: [1; NUM_OPCODES],
+            mem_access: 1,
+            mmio_wait: 4,
+        };
+        for (mnemonic, cycles) in [("mul", 4), ("mulu", 4), ("div", 12), ("divu", 12)] {
+            table.set(mnemonic, cycles).unwrap();
+        }
+        table
+    }
+}
+
+impl CostTable {
+    /// Reads and parses a cost table file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read cost table `{}`: {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Parses the text of a cost table, starting from the default costs.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut table = Self::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let lineno = i + 1;
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(cycles), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(format!("line {lineno}: expected `NAME CYCLES`"));
+            };
+            let cycles = cycles
+                .parse()
+                .map_err(|_| format!("line {lineno}: invalid number of cycles `{cycles}`"))?;
+
+            match name {
+                "mem" => table.mem_access = cycles,
+                "mmio" => table.mmio_wait = cycles,
+                mnemonic => table
+                    .set(mnemonic, cycles)
+                    .map_err(|e| format!("line {lineno}: {e}"))?,
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Sets the cost of the instruction with the given mnemonic.
+    pub fn set(&mut self, mnemonic: &str, cycles: u64) -> Result<(), String> {
+        let opcode = (0..NUM_OPCODES as u8)
+            .find(|&op| opcode_name(op).is_some_and(|name| name == mnemonic))
+            .ok_or_else(|| format!("unknown instruction `{mnemonic}`"))?;
+        self.instrs[opcode as usize] = cycles;
+        Ok(())
+    }
+
+    /// The cycles taken by `instr`, not counting its memory accesses.
+    pub fn instr_cost(&self, instr: &Instr) -> u64 {
+        self.instrs[instr.opcode() as usize]
+    }
+
+    /// The extra cycles taken by a load or store to `addr`.
+    pub fn mem_access_cost(&self, addr: u16) -> u64 {
+        if Memory::is_device_register(addr) {
+            self.mem_access + self.mmio_wait
+        } else {
+            self.mem_access
+        }
+    }
+}
+
+/// The mnemonic of an opcode, or `None` if it isn't a valid opcode.
+fn opcode_name(opcode: u8) -> Option<String> {
+    let names = [
+        OpcodeOp::try_from(opcode).map(|op| op.to_string()).ok(),
+        OpcodeAddr::try_from(opcode).map(|op| op.to_string()).ok(),
+        OpcodeImm::try_from(opcode).map(|op| op.to_string()).ok(),
+        OpcodeReg::try_from(opcode).map(|op| op.to_string()).ok(),
+        OpcodeRegImm::try_from(opcode).map(|op| op.to_string()).ok(),
+        OpcodeRegReg::try_from(opcode).map(|op| op.to_string()).ok(),
+        OpcodeRegRegReg::try_from(opcode)
+            .map(|op| op.to_string())
+            .ok(),
+        OpcodeRegRegImm::try_from(opcode)
+            .map(|op| op.to_string())
+            .ok(),
+    ];
+    names.into_iter().flatten().next()
+}
+
+impl Cpu {
+    pub fn with_cost_table(mut self, cost_table: CostTable) -> Self {
+        self.cost_table = cost_table;
+        self
+    }
+
+    /// The number of clock cycles taken so far.
+    pub fn cycles(&self) -> u64 {
+        self.mem.mmio.cycles
+    }
+
+    /// Adds the cost of executing `instr` to the cycle counter.
+    pub(super) fn charge_instr(&mut self, instr: &Instr) {
+        self.mem.mmio.cycles += self.cost_table.instr_cost(instr);
+    }
+
+    /// Adds the cost of a load or store to `addr` to the cycle counter.
+    pub(super) fn charge_mem_access(&mut self, addr: u16) {
+        self.mem.mmio.cycles += self.cost_table.mem_access_cost(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{regs::Reg, MemBlock, StepOutcome};
+
+    #[test]
+    fn parse_cost_table() {
+        let table = CostTable::parse("; costs\nmul 3\n\nmem 2\nmmio 7\n").unwrap();
+        assert_eq!(table.instrs[crate::cpu::opcodes::MUL as usize], 3);
+        assert_eq!(table.instrs[crate::cpu::opcodes::DIV as usize], 12);
+        assert_eq!(table.instrs[crate::cpu::opcodes::ADD as usize], 1);
+        assert_eq!(table.mem_access_cost(Memory::USER_START), 2);
+        assert_eq!(table.mem_access_cost(CYCLES_ADDR), 9);
+
+        assert!(CostTable::parse("frobnicate 3").is_err());
+        assert!(CostTable::parse("mul").is_err());
+        assert!(CostTable::parse("mul -1").is_err());
+    }
+
+    #[test]
+    fn count_cycles() {
+        // li $t0, 3; mul $t0, $t0; mvlo $t0; sw 0x16($zero), $t0;
+        // sb 0x17($zero), $t0; lw $t1, 0x16($zero); halt
+        let rom = MemBlock::from_vec(vec![
+            0x42, 0x40, 0x00, 0xC0, 0x8A, 0x64, 0xAA, 0x40, 0x54, 0x24, 0x16, 0x58, 0x24, 0x17,
+            0x46, 0x80, 0x16, 0x04,
+        ])
+        .unwrap();
+        let mut cpu = Cpu::headless(rom);
+
+        // `li`, then 4 for the `mul`, then `mvlo`.
+        assert_eq!(cpu.run_for(3), StepOutcome::Continued);
+        assert_eq!(cpu.cycles(), 6);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 9);
+
+        // The stores to the cycle counter are ignored, and each access costs
+        // 1 + 4 wait states on top of the instruction's cycle.
+        assert_eq!(cpu.run_for(3), StepOutcome::Continued);
+        assert_eq!(cpu.cycles(), 6 + 3 * 6);
+        // The load's own cycles are counted before it reads the low word.
+        assert_eq!(cpu.regs.get::<u16>(Reg::T1), 24);
+    }
+}
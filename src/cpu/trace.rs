@@ -0,0 +1,568 @@
+//! Structured instruction traces, with one record per executed instruction.
+//!
+//! Each record holds the cycle index, `pc`, raw `ir`, the decoded mnemonic and
+//! operands, the registers the instruction changed and the memory it
+//! accessed. Traces are written as [JSON Lines](https://jsonlines.org/) or as
+//! CSV, where the register writes and memory accesses are `;`-separated lists
+//! like `$sp=0xEFFA` and `W2 0xEFFA=0x0804` (a two byte write).
+
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use bitvec::prelude::*;
+use serde_json::{json, Value};
+
+use super::{instr::Instr, regs::RegisterFile, watchpoints::Access, Cpu, LogMsg};
+
+/// The first line of a CSV trace.
+pub const CSV_HEADER: &str = "cycle,pc,ir,mnemonic,operands,reg_writes,mem_accesses";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Self::Jsonl),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!(
+                "unknown trace format `{s}` (expected `jsonl` or `csv`)"
+            )),
+        }
+    }
+}
+
+/// A memory access made by a traced instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct MemAccess {
+    pub access: Access,
+    pub addr: u16,
+    /// Number of bytes accessed.
+    pub len: u16,
+    /// The value read or written.
+    pub value: u16,
+}
+
+impl fmt::Display for MemAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.access {
+            Access::Read => 'R',
+            Access::Write => 'W',
+        };
+        let width = 2 * self.len as usize;
+        write!(
+            f,
+            "{kind}{} 0x{:04X}=0x{:0width$X}",
+            self.len, self.addr, self.value
+        )
+    }
+}
+
+/// The record of a single executed instruction.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// Index of the instruction in the trace, starting at zero.
+    pub cycle: u64,
+    pub pc: u16,
+    pub ir: u32,
+    pub mnemonic: String,
+    pub operands: Vec<String>,
+    /// The registers the instruction changed (including `$lo` and `$hi`) and
+    /// their new values.
+    pub reg_writes: Vec<(String, u16)>,
+    pub mem: Vec<MemAccess>,
+}
+
+impl TraceRecord {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "cycle": self.cycle,
+            "pc": self.pc,
+            "ir": self.ir,
+            "mnemonic": self.mnemonic,
+            "operands": self.operands,
+            "reg_writes": self.reg_writes
+                .iter()
+                .map(|(reg, value)| json!({ "reg": reg, "value": value }))
+                .collect::<Vec<_>>(),
+            "mem": self.mem
+                .iter()
+                .map(|m| json!({
+                    "access": match m.access {
+                        Access::Read => "read",
+                        Access::Write => "write",
+                    },
+                    "addr": m.addr,
+                    "len": m.len,
+                    "value": m.value,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// Parses a record written by [`to_json`](TraceRecord::to_json).
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+            value
+                .get(name)
+                .ok_or_else(|| format!("missing field `{name}`"))
+        }
+        fn uint<T: TryFrom<u64>>(value: &Value, name: &str) -> Result<T, String> {
+            field(value, name)?
+                .as_u64()
+                .and_then(|n| T::try_from(n).ok())
+                .ok_or_else(|| format!("field `{name}` is not a valid number"))
+        }
+        fn string(value: &Value, name: &str) -> Result<String, String> {
+            field(value, name)?
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("field `{name}` is not a string"))
+        }
+        fn array<'a>(value: &'a Value, name: &str) -> Result<&'a Vec<Value>, String> {
+            field(value, name)?
+                .as_array()
+                .ok_or_else(|| format!("field `{name}` is not an array"))
+        }
+
+        let operands = array(value, "operands")?
+            .iter()
+            .map(|op| op.as_str().map(str::to_string))
+            .collect::<Option<_>>()
+            .ok_or("field `operands` is not an array of strings")?;
+        let reg_writes = array(value, "reg_writes")?
+            .iter()
+            .map(|w| Ok((string(w, "reg")?, uint(w, "value")?)))
+            .collect::<Result<_, String>>()?;
+        let mem = array(value, "mem")?
+            .iter()
+            .map(|m| {
+                let access = match string(m, "access")?.as_str() {
+                    "read" => Access::Read,
+                    "write" => Access::Write,
+                    other => return Err(format!("unknown memory access `{other}`")),
+                };
+                Ok(MemAccess {
+                    access,
+                    addr: uint(m, "addr")?,
+                    len: uint(m, "len")?,
+                    value: uint(m, "value")?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            cycle: uint(value, "cycle")?,
+            pc: uint(value, "pc")?,
+            ir: uint(value, "ir")?,
+            mnemonic: string(value, "mnemonic")?,
+            operands,
+            reg_writes,
+            mem,
+        })
+    }
+
+    /// Parses a line of a CSV trace, other than the header line. Operands are
+    /// split at spaces.
+    pub fn from_csv(line: &str) -> Result<Self, String> {
+        fn hex<T: TryFrom<u64>>(s: &str, name: &str) -> Result<T, String> {
+            s.strip_prefix("0x")
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .and_then(|n| T::try_from(n).ok())
+                .ok_or_else(|| format!("`{name}` is not a valid hex number: `{s}`"))
+        }
+        fn list(s: &str) -> impl Iterator<Item = &str> {
+            s.split(';').filter(|item| !item.is_empty())
+        }
+
+        let fields = split_csv_line(line)?;
+        let [cycle, pc, ir, mnemonic, operands, reg_writes, mem] = &fields[..] else {
+            return Err(format!("expected 7 fields, found {}", fields.len()));
+        };
+
+        let reg_writes = list(reg_writes)
+            .map(|write| {
+                let (reg, value) = write
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid register write `{write}`"))?;
+                Ok((reg.to_string(), hex(value, "reg_writes")?))
+            })
+            .collect::<Result<_, String>>()?;
+        let mem = list(mem)
+            .map(|access| {
+                let invalid = || format!("invalid memory access `{access}`");
+                let (kind, rest) = access.split_at_checked(1).ok_or_else(invalid)?;
+                let access_kind = match kind {
+                    "R" => Access::Read,
+                    "W" => Access::Write,
+                    _ => return Err(invalid()),
+                };
+                let (len, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+                let (addr, value) = rest.split_once('=').ok_or_else(invalid)?;
+                Ok(MemAccess {
+                    access: access_kind,
+                    addr: hex(addr, "mem_accesses")?,
+                    len: len.parse().map_err(|_| invalid())?,
+                    value: hex(value, "mem_accesses")?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            cycle: cycle
+                .parse()
+                .map_err(|_| format!("`cycle` is not a valid number: `{cycle}`"))?,
+            pc: hex(pc, "pc")?,
+            ir: hex(ir, "ir")?,
+            mnemonic: mnemonic.clone(),
+            operands: operands.split_whitespace().map(str::to_string).collect(),
+            reg_writes,
+            mem,
+        })
+    }
+
+    fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        let reg_writes: Vec<String> = self
+            .reg_writes
+            .iter()
+            .map(|(reg, value)| format!("{reg}=0x{value:04X}"))
+            .collect();
+        let mem: Vec<String> = self.mem.iter().map(MemAccess::to_string).collect();
+        writeln!(
+            out,
+            "{},0x{:04X},0x{:08X},{},{},{},{}",
+            self.cycle,
+            self.pc,
+            self.ir,
+            self.mnemonic,
+            csv_field(&self.operands.join(" ")),
+            csv_field(&reg_writes.join(";")),
+            csv_field(&mem.join(";")),
+        )
+    }
+}
+
+/// The state saved before an instruction executes, to find what it changed.
+struct Pending {
+    pc: u16,
+    ir: u32,
+    regs: RegisterFile,
+    hi: u16,
+    lo: u16,
+    mem: Vec<MemAccess>,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    /// Index of the next instruction to be executed.
+    cycle: u64,
+    pending: Option<Pending>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, format: TraceFormat) -> io::Result<Self> {
+        let mut tracer = Self {
+            out,
+            format,
+            cycle: 0,
+            pending: None,
+        };
+        if format == TraceFormat::Csv {
+            writeln!(tracer.out, "{CSV_HEADER}")?;
+        }
+        Ok(tracer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Cpu {
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Flushes the trace file, if tracing. Call this before exiting.
+    pub fn flush_trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.flush() {
+                eprintln!("!!! Error: failed to write trace: {e}");
+            }
+        }
+    }
+
+    /// Starts tracing the instruction about to be executed.
+    pub(super) fn begin_trace_record(&mut self) {
+        let Some(tracer) = &mut self.tracer else {
+            return;
+        };
+        tracer.pending = Some(Pending {
+            pc: self.pc,
+            ir: self.ir,
+            regs: self.regs.clone(),
+            hi: self.hi.as_u16(),
+            lo: self.lo.as_u16(),
+            mem: Vec::new(),
+        });
+    }
+
+    pub(super) fn trace_mem_access(&mut self, access: Access, addr: u16, len: u16, value: u16) {
+        if let Some(pending) = self.tracer.as_mut().and_then(|t| t.pending.as_mut()) {
+            pending.mem.push(MemAccess {
+                access,
+                addr,
+                len,
+                value,
+            });
+        }
+    }
+
+    /// Writes the trace record of the instruction which just executed.
+    /// Instructions which failed to execute aren't traced.
+    pub(super) fn end_trace_record(&mut self, executed: bool) {
+        let Some(pending) = self.tracer.as_mut().and_then(|t| t.pending.take()) else {
+            return;
+        };
+        if !executed {
+            return;
+        }
+
+        let (mnemonic, operands) = match Instr::from_bits(pending.ir.view_bits::<Msb0>()) {
+            Ok(instr) => {
+                let text = instr.display_at(pending.pc, &self.symbols).to_string();
+                match text.split_once('\t') {
+                    Some((mnemonic, operands)) => (
+                        mnemonic.to_string(),
+                        operands.split(", ").map(str::to_string).collect(),
+                    ),
+                    None => (text, Vec::new()),
+                }
+            }
+            Err(_) => ("???".to_string(), Vec::new()),
+        };
+
+        let mut reg_writes: Vec<(String, u16)> = self
+            .regs
+            .iter()
+            .filter(|&(reg, value)| pending.regs.get::<u16>(reg) != value.as_u16())
+            .map(|(reg, value)| (reg.to_string(), value.as_u16()))
+            .collect();
+        if self.lo.as_u16() != pending.lo {
+            reg_writes.push(("$lo".to_string(), self.lo.as_u16()));
+        }
+        if self.hi.as_u16() != pending.hi {
+            reg_writes.push(("$hi".to_string(), self.hi.as_u16()));
+        }
+
+        let tracer = self.tracer.as_mut().unwrap();
+        let record = TraceRecord {
+            cycle: tracer.cycle,
+            pc: pending.pc,
+            ir: pending.ir,
+            mnemonic,
+            operands,
+            reg_writes,
+            mem: pending.mem,
+        };
+        tracer.cycle += 1;
+
+        let result = match tracer.format {
+            TraceFormat::Jsonl => writeln!(tracer.out, "{}", record.to_json()),
+            TraceFormat::Csv => record.write_csv(&mut tracer.out),
+        };
+
+        if let Err(e) = result {
+            self.tracer = None;
+            self.log(LogMsg::Error(format!(
+                "failed to write trace, stopping: {e}"
+            )));
+        }
+    }
+}
+
+/// Quotes a CSV field if it needs it.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Splits a CSV line into its fields, unquoting those written by
+/// [`csv_field`].
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quoted field".into()),
+                }
+            }
+        }
+        while let Some(c) = chars.next_if(|&c| c != ',') {
+            field.push(c);
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::cpu::{MemBlock, StepOutcome};
+
+    /// A trace sink which can be read back after the CPU is done with it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traces_headless_run() {
+        #[rustfmt::skip]
+        const ROM: &[u8] = &[
+            0x42, 0x44, 0x8D, 0x00, // 0x0800: li $t0, 0x1234
+            0x42, 0x80, 0x40, 0x00, // 0x0804: li $t1, 0x0100
+            0x8A, 0x68,             // 0x0808: mul $t0, $t1
+            0x40, 0xC6, 0x00, 0x00, // 0x080A: li $a0, 0x1800
+            0x54, 0xE4, 0x00,       // 0x080E: sw 0($a0), $t0
+            0x04,                   // 0x0811: halt
+        ];
+
+        let buf = SharedBuf::default();
+        let tracer = Tracer::new(Box::new(buf.clone()), TraceFormat::Jsonl).unwrap();
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom).with_tracer(tracer);
+        assert_eq!(cpu.run(), StepOutcome::Halted);
+        cpu.flush_trace();
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<TraceRecord> = out
+            .lines()
+            .map(|line| TraceRecord::from_json(&serde_json::from_str(line).unwrap()).unwrap())
+            .collect();
+        let summary: Vec<String> = records
+            .iter()
+            .map(|r| {
+                let writes: Vec<String> = r
+                    .reg_writes
+                    .iter()
+                    .map(|(reg, value)| format!("{reg}=0x{value:04X}"))
+                    .collect();
+                let mem: Vec<String> = r.mem.iter().map(MemAccess::to_string).collect();
+                format!(
+                    "{} 0x{:04X} {} [{}] [{}]",
+                    r.cycle,
+                    r.pc,
+                    r.mnemonic,
+                    writes.join(" "),
+                    mem.join(" ")
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "0 0x0800 li [$t0=0x1234] []",
+                "1 0x0804 li [$t1=0x0100] []",
+                "2 0x0808 mul [$lo=0x3400 $hi=0x0012] []",
+                "3 0x080A li [$a0=0x1800] []",
+                "4 0x080E sw [] [W2 0x1800=0x1234]",
+                "5 0x0811 halt [] []",
+            ]
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let record = TraceRecord {
+            cycle: 2,
+            pc: 0x0808,
+            ir: 0x57C80028,
+            mnemonic: "sw".into(),
+            operands: vec!["0x0000s16($sp)".into(), "$ra".into()],
+            reg_writes: vec![("$lo".into(), 7)],
+            mem: vec![MemAccess {
+                access: Access::Write,
+                addr: 0xEFFA,
+                len: 2,
+                value: 0x0804,
+            }],
+        };
+        let parsed = TraceRecord::from_json(&record.to_json()).unwrap();
+        assert_eq!(parsed.to_json(), record.to_json());
+        assert_eq!(parsed.mem[0].to_string(), "W2 0xEFFA=0x0804");
+
+        assert!(TraceRecord::from_json(&json!({ "cycle": 0 })).is_err());
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let record = TraceRecord {
+            cycle: 3,
+            pc: 0x080C,
+            ir: 0x4240_0080,
+            mnemonic: "lw".into(),
+            operands: vec!["$t0".into(), "0x0002s16($sp)".into()],
+            reg_writes: vec![("$t0".into(), 0x1234), ("$lo".into(), 1)],
+            mem: vec![
+                MemAccess {
+                    access: Access::Read,
+                    addr: 0xEFFC,
+                    len: 2,
+                    value: 0x1234,
+                },
+                MemAccess {
+                    access: Access::Write,
+                    addr: 0x0900,
+                    len: 1,
+                    value: 0x07,
+                },
+            ],
+        };
+        let mut line = Vec::new();
+        record.write_csv(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        let parsed = TraceRecord::from_csv(line.trim_end()).unwrap();
+        assert_eq!(parsed.to_json(), record.to_json());
+
+        assert_eq!(
+            split_csv_line(r#"a,"b,""c""",,d"#).unwrap(),
+            ["a", "b,\"c\"", "", "d"]
+        );
+        assert!(split_csv_line(r#"a,"b"#).is_err());
+        assert!(TraceRecord::from_csv("1,0x0800").is_err());
+        assert!(TraceRecord::from_csv("1,0x0800,0x0,nop,,,X2 0x0=0x0").is_err());
+    }
+}
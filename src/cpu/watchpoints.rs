@@ -0,0 +1,146 @@
+//! Watchpoints pause execution when the running program accesses a range of
+//! memory. Only accesses made by instructions (`lw`, `sw`, etc.) are watched,
+//! not those made by the debugger.
+
+use super::Cpu;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Triggered by stores.
+    Write,
+    /// Triggered by loads.
+    Read,
+    /// Triggered by loads and stores.
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            Self::Write => access == Access::Write,
+            Self::Read => access == Access::Read,
+            Self::Access => true,
+        }
+    }
+}
+
+impl std::fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Write => "write",
+            Self::Read => "read",
+            Self::Access => "access",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A kind of memory access made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    /// Number of bytes watched starting at `addr`.
+    pub len: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn overlaps(&self, addr: u16, len: u16) -> bool {
+        let (start, end) = (self.addr as u32, self.addr as u32 + self.len as u32);
+        let (acc_start, acc_end) = (addr as u32, addr as u32 + len as u32);
+        start < acc_end && acc_start < end
+    }
+}
+
+/// Describes the most recent access which triggered a watchpoint.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    /// Address of the instruction which made the access.
+    pub pc: u16,
+    /// The address that was accessed.
+    pub addr: u16,
+    pub access: Access,
+}
+
+impl Cpu {
+    /// Checks an access of `len` bytes at `addr` against the watchpoints,
+    /// pausing execution before the next instruction if one is triggered.
+    pub(super) fn check_watchpoints(&mut self, addr: u16, len: u16, access: Access) {
+        let hit = self
+            .watchpoints
+            .iter()
+            .find(|wp| wp.kind.matches(access) && wp.overlaps(addr, len));
+
+        if let Some(&watchpoint) = hit {
+            self.watch_hit = Some(WatchHit {
+                watchpoint,
+                pc: self.pc,
+                addr,
+                access,
+            });
+            self.history.mark_watch_hit();
+            self.in_debug_mode = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{MemBlock, StepOutcome};
+
+    #[test]
+    fn overlap() {
+        let wp = Watchpoint {
+            addr: 0x1800,
+            len: 2,
+            kind: WatchKind::Write,
+        };
+        assert!(wp.overlaps(0x17FF, 2));
+        assert!(wp.overlaps(0x1801, 1));
+        assert!(!wp.overlaps(0x17FE, 2));
+        assert!(!wp.overlaps(0x1802, 2));
+
+        let last = Watchpoint {
+            addr: 0xFFFF,
+            len: 1,
+            ..wp
+        };
+        assert!(last.overlaps(0xFFFE, 2));
+    }
+
+    #[test]
+    fn write_watchpoint_pauses() {
+        // `li $t1, 0x1800`, `li $t0, 0x1234`, `sw 0($t1), $t0` and `halt`.
+        let rom = vec![
+            0x42, 0x86, 0x00, 0x00, 0x42, 0x44, 0x8D, 0x00, 0x56, 0xA4, 0x00, 0x04,
+        ];
+        let mut cpu = Cpu::headless(MemBlock::from_vec(rom).unwrap());
+        cpu.watchpoints.push(Watchpoint {
+            addr: 0x1801,
+            len: 1,
+            kind: WatchKind::Write,
+        });
+        cpu.watchpoints.push(Watchpoint {
+            addr: 0x1800,
+            len: 2,
+            kind: WatchKind::Read,
+        });
+
+        assert_eq!(cpu.run(), StepOutcome::Breakpoint);
+        assert_eq!(cpu.pc, 0x080B);
+        let hit = cpu.watch_hit.unwrap();
+        assert_eq!(
+            (hit.pc, hit.addr, hit.access),
+            (0x0808, 0x1800, Access::Write)
+        );
+        assert_eq!(hit.watchpoint.kind, WatchKind::Write);
+    }
+}
@@ -0,0 +1,828 @@
+//! A server for the [Debug Adapter Protocol][dap] (DAP), so editors can launch
+//! and debug ROMs. Messages are read from stdin and written to stdout; the
+//! VM's own diagnostics still go to stderr.
+//!
+//! The program is single threaded (DAP thread id 1). Registers are exposed as
+//! variables in three scopes: the general-purpose registers, the
+//! special-purpose registers, and the words at the top of the stack. Stack
+//! words and pointer registers carry a memory reference, so editors can open
+//! them in a memory view.
+//!
+//! [dap]: https://microsoft.github.io/debug-adapter-protocol/
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc, Mutex,
+    },
+};
+
+use serde_json::{json, Value};
+
+use crate::cpu::{
+    regs::Reg,
+    srcmap::{SourceMap, SrcLoc},
+    symbols::SymbolTable,
+    Cpu, LogMsg, MemBlock, MemRw, Memory, Signal, Spr, StepOutcome,
+};
+
+const THREAD_ID: u64 = 1;
+
+const REGISTERS_REF: u64 = 1;
+const SPECIAL_REGISTERS_REF: u64 = 2;
+const STACK_REF: u64 = 3;
+
+/// Number of stack words shown in the stack scope.
+const STACK_WORDS: u16 = 16;
+
+/// How many instructions to run between checks for new requests (such as
+/// `pause`) while the program is running.
+const BATCH_SIZE: u32 = 1024;
+
+/// Serves a single debug session, returning once the client disconnects.
+pub fn serve(input: impl Read + Send + 'static, output: impl Write) -> io::Result<()> {
+    let (request_tx, request_rx) = mpsc::channel();
+
+    // Read requests on another thread so that they can be checked for while
+    // the program is running.
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        loop {
+            match read_message(&mut reader) {
+                Ok(Some(msg)) => {
+                    if request_tx.send(msg).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("DAP: failed to read message: {e}");
+                    break;
+                }
+            }
+        }
+    });
+
+    Session::new(output, request_rx).run()
+}
+
+/// Reads one `Content-Length`-framed JSON message. Returns `None` at EOF.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = value.trim().parse().ok();
+        }
+    }
+
+    let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// The VM being debugged.
+struct Target {
+    cpu: Cpu,
+    logger_rx: Receiver<Signal>,
+}
+
+impl Target {
+    fn launch(args: &Value) -> Result<Self, String> {
+        let program = args["program"]
+            .as_str()
+            .map(PathBuf::from)
+            .ok_or("`program` must be the path to a ROM file")?;
+        let path_arg = |name: &str| args[name].as_str().map(PathBuf::from);
+
+        let vec = std::fs::read(&program)
+            .map_err(|e| format!("could not read ROM file `{}`: {e}", program.display()))?;
+        let rom = MemBlock::from_vec(vec)
+            .ok_or_else(|| format!("ROM file `{}` is too large", program.display()))?;
+
+        let src_map = match path_arg("srcMap").or_else(|| SourceMap::default_path(&program)) {
+            Some(path) => Some(SourceMap::load(&path)?),
+            None => None,
+        };
+        let symbols = match path_arg("symbols").or_else(|| SymbolTable::default_path(&program)) {
+            Some(path) => SymbolTable::load(&path)?,
+            None => SymbolTable::new(),
+        };
+
+        let vtty = Arc::new(Mutex::new(MemBlock::new_zeroed()));
+        let (logger_tx, logger_rx) = mpsc::channel();
+        let (_interrupt_tx, interrupt_rx) = mpsc::channel();
+
+        let mut cpu = Cpu::new(rom, vtty, logger_tx, interrupt_rx)
+            .with_start_addr(Memory::ROM_START)
+            .in_debug_mode(true)
+            .with_src_map(src_map)
+            .with_symbols(symbols);
+        cpu.external_debugger = true;
+        cpu.fetch();
+
+        Ok(Self { cpu, logger_rx })
+    }
+
+    /// Finds the first address of a source line, as requested by an editor.
+    /// Editors use absolute paths, while source maps usually hold relative
+    /// ones, so the path relative to the working directory and the bare file
+    /// name are tried too.
+    fn resolve_line(&self, file: &Path, line: u32) -> Option<(u16, u32)> {
+        let src_map = self.cpu.src_map.as_ref()?;
+        let cwd = std::env::current_dir().ok();
+        let candidates = [
+            Some(file),
+            cwd.as_deref().and_then(|cwd| file.strip_prefix(cwd).ok()),
+            file.file_name().map(Path::new),
+        ];
+        candidates
+            .into_iter()
+            .flatten()
+            .find_map(|file| src_map.resolve_line(file, line))
+            .map(|(addr, loc)| (addr, loc.line))
+    }
+}
+
+/// How far to run before stopping with reason `step`.
+enum RunMode {
+    /// Until a breakpoint, watchpoint, or the end of the program.
+    Continue,
+    /// Until a different source line is reached. With a `max_depth`, lines in
+    /// deeper calls don't count, which steps over calls.
+    Line {
+        from: SrcLoc,
+        max_depth: Option<usize>,
+    },
+    /// A single instruction.
+    Instruction,
+    /// Until the call stack is shallower than `depth`.
+    Out { depth: usize },
+}
+
+struct Session<W: Write> {
+    out: W,
+    seq: u64,
+    requests: Receiver<Value>,
+    target: Option<Target>,
+    /// Set while the program is running.
+    running: Option<RunMode>,
+    /// The breakpoint addresses set in each source file, so that they can be
+    /// replaced by the next `setBreakpoints` request for that file.
+    src_breakpoints: HashMap<PathBuf, Vec<u16>>,
+    stop_on_entry: bool,
+    /// Events to send once the response to the current request is sent.
+    pending_events: Vec<(&'static str, Value)>,
+    done: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn new(out: W, requests: Receiver<Value>) -> Self {
+        Self {
+            out,
+            seq: 0,
+            requests,
+            target: None,
+            running: None,
+            src_breakpoints: HashMap::new(),
+            stop_on_entry: false,
+            pending_events: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        while !self.done {
+            if self.running.is_some() {
+                match self.requests.try_recv() {
+                    Ok(request) => self.handle(request)?,
+                    Err(TryRecvError::Empty) => self.run_batch()?,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match self.requests.recv() {
+                    Ok(request) => self.handle(request)?,
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut msg: Value) -> io::Result<()> {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+        let body = msg.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.out.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn output(&mut self, category: &str, output: String) -> io::Result<()> {
+        self.event("output", json!({ "category": category, "output": output }))
+    }
+
+    fn handle(&mut self, request: Value) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsStepBack": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.pending_events.push(("stopped", stopped_body("entry")));
+                } else {
+                    self.resume(RunMode::Continue);
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.with_target(Self::stack_trace),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Special Registers", "variablesReference": SPECIAL_REGISTERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            ]})),
+            "variables" => {
+                let reference = args["variablesReference"].as_u64().unwrap_or_default();
+                self.with_target(|target| variables(target, reference))
+            }
+            "readMemory" => self.with_target(|target| read_memory(target, args)),
+            "continue" => self.step_request(|_| Some(RunMode::Continue)),
+            "next" => {
+                let granularity = args["granularity"].as_str();
+                self.step_request(|cpu| line_step(cpu, granularity, true))
+            }
+            "stepIn" => {
+                let granularity = args["granularity"].as_str();
+                self.step_request(|cpu| line_step(cpu, granularity, false))
+            }
+            "stepOut" => self.step_request(|cpu| {
+                Some(RunMode::Out {
+                    depth: cpu.call_stack.depth(),
+                })
+            }),
+            "stepBack" => self.reverse(false),
+            "reverseContinue" => self.reverse(true),
+            "pause" => {
+                if self.running.is_some() {
+                    self.park();
+                    self.pending_events.push(("stopped", stopped_body("pause")));
+                }
+                Ok(Value::Null)
+            }
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request `{command}`")),
+        };
+
+        let response = match result {
+            Ok(body) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": true,
+                "body": body,
+            }),
+            Err(message) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": false,
+                "message": message,
+            }),
+        };
+        self.send(response)?;
+
+        for (event, body) in std::mem::take(&mut self.pending_events) {
+            self.event(event, body)?;
+        }
+        Ok(())
+    }
+
+    fn with_target(
+        &mut self,
+        f: impl FnOnce(&mut Target) -> Result<Value, String>,
+    ) -> Result<Value, String> {
+        match &mut self.target {
+            Some(target) => f(target),
+            None => Err("no program has been launched".into()),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        self.target = Some(Target::launch(args)?);
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.pending_events.push(("initialized", Value::Null));
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .map(PathBuf::from)
+            .ok_or("breakpoints must be set in a source file with a path")?;
+        let lines: Vec<u64> = args["breakpoints"]
+            .as_array()
+            .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).collect())
+            .unwrap_or_default();
+
+        let Some(target) = &mut self.target else {
+            let unverified = lines
+                .iter()
+                .map(|line| json!({ "verified": false, "line": line }));
+            return Ok(json!({ "breakpoints": unverified.collect::<Vec<_>>() }));
+        };
+
+        for addr in self.src_breakpoints.remove(&path).unwrap_or_default() {
+            target.cpu.breakpoints.remove(&addr);
+        }
+
+        let mut addrs = Vec::new();
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            match target.resolve_line(&path, line as u32) {
+                Some((addr, actual_line)) => {
+                    target.cpu.breakpoints.insert(addr);
+                    addrs.push(addr);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "line": actual_line,
+                        "instructionReference": format!("0x{addr:04X}"),
+                    }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code was generated for this line",
+                })),
+            }
+        }
+        self.src_breakpoints.insert(path, addrs);
+
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(target: &mut Target) -> Result<Value, String> {
+        let cpu = &target.cpu;
+        let frames = cpu.call_stack.frames();
+        let callee = |i: usize| match i.checked_sub(1) {
+            Some(i) => cpu.symbols.fmt_addr(frames[i].callee),
+            None => "<entry>".to_string(),
+        };
+        let frame = |id: usize, name: String, addr: u16| {
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("0x{addr:04X}"),
+            });
+            if let Some(loc) = cpu.src_loc(addr) {
+                frame["source"] = source(&loc.file);
+                frame["line"] = json!(loc.line);
+                frame["column"] = json!(1);
+            }
+            frame
+        };
+
+        // Outer frames are shown at their call site.
+        let mut stack_frames = vec![frame(0, callee(frames.len()), cpu.pc)];
+        for (n, i) in (0..frames.len()).rev().enumerate() {
+            stack_frames.push(frame(n + 1, callee(i), frames[i].call_site));
+        }
+
+        Ok(json!({
+            "stackFrames": stack_frames,
+            "totalFrames": frames.len() + 1,
+        }))
+    }
+
+    /// Handles a request which resumes execution in the given mode. `mode`
+    /// returning `None` means stepping isn't possible, so the program is
+    /// stopped again straight away.
+    fn step_request(
+        &mut self,
+        mode: impl FnOnce(&Cpu) -> Option<RunMode>,
+    ) -> Result<Value, String> {
+        let Some(target) = &self.target else {
+            return Err("no program has been launched".into());
+        };
+        let mode = mode(&target.cpu).unwrap_or(RunMode::Instruction);
+        self.resume(mode);
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    fn resume(&mut self, mode: RunMode) {
+        let Some(target) = &mut self.target else {
+            return;
+        };
+
+        // Execute the instruction we're stopped at even if it has a
+        // breakpoint on it. The rest of the run is done by `run_batch`.
+        target.cpu.in_debug_mode = false;
+        target.cpu.watch_hit = None;
+        match target.cpu.execute() {
+            Ok(()) => self.running = Some(mode),
+            Err(e) => {
+                target.cpu.in_debug_mode = true;
+                let output = format!("!!! Error: {e:?}\n");
+                self.pending_events.extend([
+                    ("output", json!({ "category": "stderr", "output": output })),
+                    ("stopped", stopped_body("exception")),
+                ]);
+            }
+        }
+    }
+
+    /// Runs the program for a while, stopping if necessary. The instruction
+    /// at the start of a run has already been executed by `resume`.
+    fn run_batch(&mut self) -> io::Result<()> {
+        for _ in 0..BATCH_SIZE {
+            if let Some(reason) = self.check_stop()? {
+                return self.stop(reason);
+            }
+            let Some(target) = &mut self.target else {
+                return Ok(());
+            };
+            if let StepOutcome::IllegalInstr
+            | StepOutcome::Exception { .. }
+            | StepOutcome::KernelCall { .. } = target.cpu.step()
+            {
+                // Report the error the CPU logged before stopping.
+                self.check_stop()?;
+                return self.stop("exception");
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles signals from the CPU and checks whether the current run is
+    /// over, returning the reason to stop.
+    fn check_stop(&mut self) -> io::Result<Option<&'static str>> {
+        let Some(target) = &mut self.target else {
+            return Ok(None);
+        };
+
+        let mut reason = None;
+        let mut outputs = Vec::new();
+        for signal in target.logger_rx.try_iter() {
+            match signal {
+                Signal::Halt => {
+                    self.running = None;
+                    self.target = None;
+                    self.event("exited", json!({ "exitCode": 0 }))?;
+                    return self.event("terminated", json!({})).map(|()| None);
+                }
+                Signal::Breakpoint => reason = Some("breakpoint"),
+                // Reported by the step's outcome.
+                Signal::IllegalInstr => {}
+                Signal::Log(LogMsg::DebugPuts { value, .. }) => {
+                    outputs.push(("stdout", format!("{value}\n")))
+                }
+                Signal::Log(LogMsg::BreakpointExn { location, .. }) => {
+                    outputs.push(("console", format!("Breakpoint Exception: {location}\n")))
+                }
+                Signal::Log(LogMsg::Error(e)) => {
+                    outputs.push(("stderr", format!("!!! Error: {e}\n")))
+                }
+                Signal::Log(_) => {}
+            }
+        }
+
+        let cpu = &mut target.cpu;
+        if reason.is_none() && cpu.in_debug_mode {
+            // `step` stops before executing an instruction with a breakpoint,
+            // and after executing one which triggers a watchpoint.
+            reason = match cpu.watch_hit.take() {
+                Some(_) => Some("data breakpoint"),
+                None => Some("breakpoint"),
+            };
+        }
+        if reason.is_none() {
+            let finished = match &self.running {
+                None | Some(RunMode::Continue) => false,
+                Some(RunMode::Instruction) => true,
+                Some(RunMode::Line { from, max_depth }) => {
+                    max_depth.is_none_or(|depth| cpu.call_stack.depth() <= depth)
+                        && cpu.src_loc(cpu.pc).is_some_and(|loc| loc != from)
+                }
+                Some(RunMode::Out { depth }) => cpu.call_stack.depth() < *depth,
+            };
+            if finished {
+                reason = Some("step");
+            }
+        }
+
+        for (category, output) in outputs {
+            self.output(category, output)?;
+        }
+        Ok(reason)
+    }
+
+    /// Stops the program, telling the editor why.
+    fn stop(&mut self, reason: &str) -> io::Result<()> {
+        self.park();
+        self.event("stopped", stopped_body(reason))
+    }
+
+    /// Stops running, leaving the CPU paused at the next instruction.
+    fn park(&mut self) {
+        self.running = None;
+        if let Some(target) = &mut self.target {
+            target.cpu.in_debug_mode = true;
+            target.cpu.fetch();
+        }
+    }
+
+    /// Steps backwards through the history, by a single step or until a
+    /// breakpoint or watchpoint hit.
+    fn reverse(&mut self, to_breakpoint: bool) -> Result<Value, String> {
+        let Some(target) = &mut self.target else {
+            return Err("no program has been launched".into());
+        };
+        let cpu = &mut target.cpu;
+
+        let mut reason = "step";
+        // Stops early at the beginning of the history.
+        while let Some(record) = cpu.reverse_step() {
+            if !to_breakpoint {
+                break;
+            }
+            if record.watch_hit {
+                reason = "data breakpoint";
+                break;
+            }
+            if cpu.breakpoints.contains(&cpu.pc) {
+                reason = "breakpoint";
+                break;
+            }
+        }
+
+        self.pending_events.push(("stopped", stopped_body(reason)));
+        Ok(Value::Null)
+    }
+}
+
+/// Chooses how to step for `next` (`over_calls`) and `stepIn` requests.
+fn line_step(cpu: &Cpu, granularity: Option<&str>, over_calls: bool) -> Option<RunMode> {
+    if granularity == Some("instruction") {
+        return Some(RunMode::Instruction);
+    }
+    Some(RunMode::Line {
+        from: cpu.src_loc(cpu.pc)?.clone(),
+        max_depth: over_calls.then(|| cpu.call_stack.depth()),
+    })
+}
+
+fn stopped_body(reason: &str) -> Value {
+    json!({
+        "reason": reason,
+        "threadId": THREAD_ID,
+        "allThreadsStopped": true,
+    })
+}
+
+fn source(path: &Path) -> Value {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    json!({
+        "name": path.file_name().map(|name| name.to_string_lossy()),
+        "path": path,
+    })
+}
+
+fn variables(target: &Target, reference: u64) -> Result<Value, String> {
+    let cpu = &target.cpu;
+    let word = |name: String, value: u16| {
+        json!({
+            "name": name,
+            "value": format!("0x{value:04X} = {value}"),
+            "variablesReference": 0,
+        })
+    };
+
+    let variables: Vec<Value> = match reference {
+        REGISTERS_REF => cpu
+            .regs
+            .iter()
+            .map(|(reg, value)| {
+                let mut var = word(reg.to_string(), value.as_u16());
+                if matches!(reg, Reg::Sp | Reg::Gp | Reg::Ra) {
+                    var["memoryReference"] = json!(format!("0x{:04X}", value.as_u16()));
+                }
+                var
+            })
+            .collect(),
+        SPECIAL_REGISTERS_REF => Spr::ALL
+            .into_iter()
+            .map(|spr| match spr {
+                Spr::Ir => json!({
+                    "name": format!("${spr}"),
+                    "value": format!("0x{:08X}", cpu.ir),
+                    "variablesReference": 0,
+                }),
+                _ => {
+                    let mut var = word(format!("${spr}"), cpu.spr(spr) as u16);
+                    if spr == Spr::Pc {
+                        var["memoryReference"] = json!(format!("0x{:04X}", cpu.pc));
+                    }
+                    var
+                }
+            })
+            .collect(),
+        STACK_REF => cpu
+            .stack_words(STACK_WORDS)
+            .enumerate()
+            .map(|(i, (addr, value))| {
+                let mut var = word(format!("[$sp+{:02}]", 2 * i), value);
+                var["memoryReference"] = json!(format!("0x{addr:04X}"));
+                var
+            })
+            .collect(),
+        _ => return Err(format!("unknown variables reference {reference}")),
+    };
+
+    Ok(json!({ "variables": variables }))
+}
+
+fn read_memory(target: &Target, args: &Value) -> Result<Value, String> {
+    let reference = args["memoryReference"].as_str().unwrap_or_default();
+    let base = reference
+        .strip_prefix("0x")
+        .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| format!("invalid memory reference `{reference}`"))?;
+    let start = base as i64 + args["offset"].as_i64().unwrap_or(0);
+    let count = args["count"].as_i64().unwrap_or(0);
+
+    let mut bytes = Vec::new();
+    for addr in start.max(0)..(start + count).min(u16::MAX as i64 + 1) {
+        // Device registers can't be read without side effects.
+        if Memory::is_device_register(addr as u16) {
+            break;
+        }
+        bytes.push(target.cpu.mem.read_u8(addr as u16));
+    }
+
+    Ok(json!({
+        "address": format!("0x{:04X}", start.clamp(0, u16::MAX as i64)),
+        "data": base64(&bytes),
+        "unreadableBytes": count - bytes.len() as i64,
+    }))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `li $t0, 2` twice, then `halt`.
+    const ROM: &[u8] = &[0x42, 0x40, 0x00, 0x80, 0x42, 0x40, 0x00, 0x80, 0x04];
+
+    #[test]
+    fn encode_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xFB, 0xFF]), "+/8=");
+    }
+
+    #[test]
+    fn framing() {
+        let mut input: &[u8] =
+            b"Content-Length: 10\r\n\r\n{\"seq\": 1}Content-Length: 2\r\nX-Other: 1\r\n\r\n[]";
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!([])));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+
+        let mut input: &[u8] = b"\r\n{}";
+        assert!(read_message(&mut input).is_err());
+    }
+
+    /// Handles a request, returning the messages sent in reply.
+    fn request(session: &mut Session<Vec<u8>>, command: &str, arguments: Value) -> Vec<Value> {
+        session
+            .handle(
+                json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }),
+            )
+            .unwrap();
+        while session.running.is_some() {
+            session.run_batch().unwrap();
+        }
+
+        let out = std::mem::take(&mut session.out);
+        let mut reader = out.as_slice();
+        let mut messages = Vec::new();
+        while let Some(msg) = read_message(&mut reader).unwrap() {
+            messages.push(msg);
+        }
+        assert_eq!(messages[0]["type"], "response");
+        assert_eq!(messages[0]["command"], command);
+        messages
+    }
+
+    fn events(messages: &[Value]) -> Vec<&str> {
+        messages[1..]
+            .iter()
+            .map(|msg| msg["event"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn session() {
+        let program = std::env::temp_dir().join(format!("lark-dap-{}.rom", std::process::id()));
+        std::fs::write(&program, ROM).unwrap();
+
+        let (_request_tx, request_rx) = mpsc::channel();
+        let mut session = Session::new(Vec::new(), request_rx);
+
+        let reply = request(&mut session, "initialize", json!({}));
+        assert_eq!(reply[0]["body"]["supportsStepBack"], true);
+
+        let reply = request(&mut session, "stackTrace", json!({}));
+        assert_eq!(reply[0]["success"], false);
+
+        let args = json!({ "program": program, "stopOnEntry": true });
+        let reply = request(&mut session, "launch", args);
+        std::fs::remove_file(&program).unwrap();
+        assert_eq!(reply[0]["success"], true);
+        assert_eq!(events(&reply), ["initialized"]);
+
+        let reply = request(&mut session, "configurationDone", json!({}));
+        assert_eq!(events(&reply), ["stopped"]);
+        assert_eq!(reply[1]["body"]["reason"], "entry");
+
+        let args = json!({ "memoryReference": "0x800", "count": 4 });
+        let reply = request(&mut session, "readMemory", args);
+        assert_eq!(reply[0]["body"]["data"], "QkAAgA==");
+
+        let args = json!({ "threadId": THREAD_ID, "granularity": "instruction" });
+        let reply = request(&mut session, "next", args);
+        assert_eq!(events(&reply), ["stopped"]);
+        assert_eq!(reply[1]["body"]["reason"], "step");
+
+        let reply = request(
+            &mut session,
+            "variables",
+            json!({ "variablesReference": 1 }),
+        );
+        let t0 = reply[0]["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|var| var["name"] == "$t0")
+            .unwrap();
+        assert_eq!(t0["value"], "0x0002 = 2");
+
+        let reply = request(&mut session, "continue", json!({ "threadId": THREAD_ID }));
+        assert_eq!(events(&reply), ["exited", "terminated"]);
+        assert!(session.target.is_none());
+
+        let reply = request(&mut session, "disconnect", json!({}));
+        assert_eq!(reply[0]["success"], true);
+        assert!(session.done);
+    }
+}
@@ -0,0 +1,684 @@
+//! A stub implementing (a subset of) the GDB Remote Serial Protocol, so that
+//! standard tooling can attach to the VM over TCP instead of using the
+//! interactive `debug>` prompt.
+//!
+//! Supported packets: `?`, `g`/`G`, `p`/`P`, `m`/`M`, `s`, `c`, `Z0`-`Z4` and
+//! `z0`-`z4`, `D`, `k`, plus the queries GDB needs to get started (including
+//! `qXfer:features:read` for the [target description](TARGET_XML)).
+//!
+//! Registers are numbered in encoding order (`$zero` = 0 through `$sp` = 15),
+//! followed by `$pc`, `$lo` and `$hi`. All are 16 bits and big-endian, like
+//! the words in Lark memory.
+
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    cpu::{
+        regs::Reg,
+        watchpoints::{WatchKind, Watchpoint},
+        Cpu, LogMsg, MemRw, Memory, Signal, StepOutcome,
+    },
+    utils::s16,
+};
+
+/// Describes the Lark register file to GDB.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lark.cpu">
+    <reg name="zero" bitsize="16" type="int" regnum="0"/>
+    <reg name="rv" bitsize="16" type="int"/>
+    <reg name="ra" bitsize="16" type="code_ptr"/>
+    <reg name="a0" bitsize="16" type="int"/>
+    <reg name="a1" bitsize="16" type="int"/>
+    <reg name="a2" bitsize="16" type="int"/>
+    <reg name="s0" bitsize="16" type="int"/>
+    <reg name="s1" bitsize="16" type="int"/>
+    <reg name="s2" bitsize="16" type="int"/>
+    <reg name="t0" bitsize="16" type="int"/>
+    <reg name="t1" bitsize="16" type="int"/>
+    <reg name="t2" bitsize="16" type="int"/>
+    <reg name="k0" bitsize="16" type="int"/>
+    <reg name="k1" bitsize="16" type="int"/>
+    <reg name="gp" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="lo" bitsize="16" type="int"/>
+    <reg name="hi" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+const NUM_GPRS: usize = 16;
+const REG_PC: usize = NUM_GPRS;
+const REG_LO: usize = NUM_GPRS + 1;
+const REG_HI: usize = NUM_GPRS + 2;
+const NUM_REGS: usize = NUM_GPRS + 3;
+
+/// How many instructions to run between checks for a `^C` from GDB.
+const INTERRUPT_POLL_INTERVAL: u32 = 1024;
+
+/// Unix signal numbers used in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Why the target stopped running.
+enum Stop {
+    Signal(u8),
+    Watch(Watchpoint, u16),
+    Exited,
+}
+
+/// Waits for GDB to connect on `127.0.0.1:port`, then serves requests until
+/// GDB detaches (in which case execution continues normally) or the program
+/// halts.
+pub fn serve(cpu: &mut Cpu, logger_rx: &Receiver<Signal>, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB to connect on 127.0.0.1:{port}...");
+    let (stream, addr) = listener.accept()?;
+    eprintln!("GDB connected from {addr}");
+
+    let mut stub = GdbStub::new(cpu, logger_rx, stream.try_clone()?, stream);
+    let result = stub.run();
+
+    stub.cpu.external_debugger = false;
+    stub.cpu.in_debug_mode = false;
+    result
+}
+
+/// The stream GDB's packets arrive on, which can be checked for a `^C`
+/// without blocking while the program runs.
+trait Incoming: Read {
+    /// Returns the next byte without consuming it, or `None` if none has
+    /// arrived yet.
+    fn peek_byte(&self) -> io::Result<Option<u8>>;
+}
+
+impl Incoming for TcpStream {
+    fn peek_byte(&self) -> io::Result<Option<u8>> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = match self.peek(&mut byte) {
+            Ok(n) => Ok((n > 0).then_some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        };
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+struct GdbStub<'a, R, W> {
+    cpu: &'a mut Cpu,
+    logger_rx: &'a Receiver<Signal>,
+    reader: BufReader<R>,
+    writer: W,
+}
+
+impl<'a, R: Incoming, W: Write> GdbStub<'a, R, W> {
+    /// Stops the CPU at its current instruction, ready for GDB to take over.
+    fn new(cpu: &'a mut Cpu, logger_rx: &'a Receiver<Signal>, reader: R, writer: W) -> Self {
+        cpu.external_debugger = true;
+        cpu.in_debug_mode = true;
+        cpu.fetch();
+        Self {
+            cpu,
+            logger_rx,
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let mut stop = None;
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => stop_reply(&Stop::Signal(SIGTRAP)),
+                Some(b'g') => self.read_registers(),
+                Some(b'G') => self.write_registers(&packet[1..]),
+                Some(b'p') => self.read_register(&packet[1..]),
+                Some(b'P') => self.write_register(&packet[1..]),
+                Some(b'm') => self.read_memory(&packet[1..]),
+                Some(b'M') => self.write_memory(&packet[1..]),
+                Some(b's') => {
+                    self.set_resume_addr(&packet[1..]);
+                    stop_reply(stop.insert(self.single_step()))
+                }
+                Some(b'c') => {
+                    self.set_resume_addr(&packet[1..]);
+                    stop_reply(stop.insert(self.resume()?))
+                }
+                Some(b'Z') => self.set_point(&packet[1..], true),
+                Some(b'z') => self.set_point(&packet[1..], false),
+                Some(b'D') => {
+                    self.send_packet("OK")?;
+                    eprintln!("GDB detached");
+                    return Ok(());
+                }
+                Some(b'k') => {
+                    eprintln!("Killed by GDB");
+                    std::process::exit(0);
+                }
+                Some(b'H') | Some(b'T') => "OK".to_string(),
+                Some(b'q') => self.query(&packet),
+                _ => String::new(),
+            };
+
+            self.send_packet(&reply)?;
+
+            if let Some(Stop::Exited) = stop {
+                eprintln!("Exiting...");
+                std::process::exit(0);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` when GDB
+    /// disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            loop {
+                if self.reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+                // Otherwise it's an ack (`+`/`-`) or a `^C` while already stopped.
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if self.reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0u8; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+
+            if expected == Some(checksum_of(&data)) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            // Ask for a retransmission and wait for it.
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        self.writer.write_all(packet.as_bytes())?;
+        self.writer.flush()
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_addr_len(args) else {
+                return "E01".to_string();
+            };
+            let xml = TARGET_XML.as_bytes();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            let chunk = String::from_utf8_lossy(&xml[start..end]);
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            format!("{marker}{chunk}")
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn get_register(&self, regno: usize) -> Option<u16> {
+        match regno {
+            0..NUM_GPRS => Some(self.cpu.regs.get(Reg::try_from(regno as u8).ok()?)),
+            REG_PC => Some(self.cpu.pc),
+            REG_LO => Some(self.cpu.lo.as_u16()),
+            REG_HI => Some(self.cpu.hi.as_u16()),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, regno: usize, value: u16) -> bool {
+        match regno {
+            0..NUM_GPRS => match Reg::try_from(regno as u8) {
+                Ok(reg) => self.cpu.regs.set(reg, value),
+                Err(_) => return false,
+            },
+            REG_PC => {
+                self.cpu.pc = value;
+                self.cpu.fetch();
+            }
+            REG_LO => self.cpu.lo = s16::from(value),
+            REG_HI => self.cpu.hi = s16::from(value),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGS)
+            .filter_map(|regno| self.get_register(regno))
+            .map(|value| format!("{value:04x}"))
+            .collect()
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let Some(values) = decode_hex(hex) else {
+            return "E01".to_string();
+        };
+        for (regno, word) in values.chunks_exact(2).take(NUM_REGS).enumerate() {
+            self.set_register(regno, u16::from_be_bytes([word[0], word[1]]));
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|regno| self.get_register(regno))
+            .map(|value| format!("{value:04x}"))
+            .unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(regno, value)| {
+            let regno = usize::from_str_radix(regno, 16).ok()?;
+            let value = decode_hex(value)?;
+            let value = u16::from_be_bytes(value.get(..2)?.try_into().ok()?);
+            Some((regno, value))
+        });
+        match parsed {
+            Some((regno, value)) if self.set_register(regno, value) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return "E01".to_string();
+        };
+
+        let mut out = String::new();
+        for i in 0..len {
+            let Some(addr) = addr.checked_add(i) else {
+                break;
+            };
+            // Device registers can't be read without side effects.
+            if Memory::is_device_register(addr) {
+                break;
+            }
+            out.push_str(&format!("{:02x}", self.cpu.mem.read_u8(addr)));
+        }
+
+        if out.is_empty() && len > 0 {
+            "E14".to_string()
+        } else {
+            out
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(addr_len, data)| {
+            let (addr, len) = parse_addr_len(addr_len)?;
+            let data = decode_hex(data)?;
+            (data.len() == len as usize).then_some((addr, data))
+        });
+        let Some((addr, data)) = parsed else {
+            return "E01".to_string();
+        };
+
+        if addr as usize + data.len() > u16::MAX as usize + 1
+            || (0..data.len() as u16).any(|i| Memory::is_device_register(addr + i))
+        {
+            return "E14".to_string();
+        }
+
+        for (i, byte) in data.iter().enumerate() {
+            self.cpu.mem.write_u8(addr + i as u16, *byte);
+        }
+        // The write may have modified the next instruction.
+        self.cpu.fetch();
+        "OK".to_string()
+    }
+
+    /// Handles `Z`/`z` packets: `TYPE,ADDR,KIND`.
+    fn set_point(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.splitn(3, ',');
+        let (Some(ty), Some(addr), Some(kind)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (u16::from_str_radix(addr, 16), u16::from_str_radix(kind, 16))
+        else {
+            return "E01".to_string();
+        };
+
+        let watch_kind = match ty {
+            "0" | "1" => {
+                if insert {
+                    self.cpu.breakpoints.insert(addr);
+                } else {
+                    self.cpu.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint {
+            addr,
+            len,
+            kind: watch_kind,
+        };
+        if insert {
+            self.cpu.watchpoints.push(watchpoint);
+        } else if let Some(i) = self.cpu.watchpoints.iter().position(|wp| *wp == watchpoint) {
+            self.cpu.watchpoints.remove(i);
+        }
+        "OK".to_string()
+    }
+
+    /// Handles the optional address argument of `s` and `c`.
+    fn set_resume_addr(&mut self, args: &str) {
+        if let Ok(addr) = u16::from_str_radix(args, 16) {
+            self.cpu.pc = addr;
+            self.cpu.fetch();
+        }
+    }
+
+    fn single_step(&mut self) -> Stop {
+        let stop = self.execute_stopped_instr();
+        // Leave the CPU stopped at the next instruction.
+        self.cpu.in_debug_mode = true;
+        self.cpu.fetch();
+        stop.unwrap_or(Stop::Signal(SIGTRAP))
+    }
+
+    /// Executes the instruction the CPU is stopped at. Returns a reason to
+    /// stop if something other than the instruction completing happened.
+    fn execute_stopped_instr(&mut self) -> Option<Stop> {
+        self.cpu.in_debug_mode = false;
+        self.cpu.watch_hit = None;
+
+        if let Err(e) = self.cpu.execute() {
+            eprintln!("!!! Error: {e:?}");
+            return Some(Stop::Signal(SIGILL));
+        }
+
+        self.handle_signals().or_else(|| self.watch_stop())
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, the program halts, or GDB
+    /// sends `^C`.
+    fn resume(&mut self) -> io::Result<Stop> {
+        // The instruction we're stopped at may have a breakpoint on it, so
+        // execute it before checking for breakpoints again.
+        if let Some(stop) = self.execute_stopped_instr() {
+            self.cpu.in_debug_mode = true;
+            self.cpu.fetch();
+            return Ok(stop);
+        }
+
+        let mut steps = 0;
+        loop {
+            let outcome = self.cpu.step();
+
+            if let Some(stop) = self.handle_signals() {
+                self.cpu.in_debug_mode = true;
+                self.cpu.fetch();
+                return Ok(stop);
+            }
+
+            if let StepOutcome::IllegalInstr
+            | StepOutcome::Exception { .. }
+            | StepOutcome::KernelCall { .. } = outcome
+            {
+                self.cpu.in_debug_mode = true;
+                self.cpu.fetch();
+                return Ok(Stop::Signal(SIGILL));
+            }
+
+            if self.cpu.in_debug_mode {
+                // A watchpoint stops us after the access, before the next
+                // instruction has been fetched.
+                self.cpu.fetch();
+                return Ok(self.watch_stop().unwrap_or(Stop::Signal(SIGTRAP)));
+            }
+
+            steps += 1;
+            if steps % INTERRUPT_POLL_INTERVAL == 0 && self.interrupt_requested()? {
+                self.cpu.in_debug_mode = true;
+                self.cpu.fetch();
+                return Ok(Stop::Signal(SIGINT));
+            }
+        }
+    }
+
+    fn watch_stop(&mut self) -> Option<Stop> {
+        let hit = self.cpu.watch_hit.take()?;
+        Some(Stop::Watch(hit.watchpoint, hit.addr))
+    }
+
+    /// Handles any signals the CPU sent during the last instruction, returning
+    /// a reason to stop if there is one.
+    fn handle_signals(&mut self) -> Option<Stop> {
+        let mut stop = None;
+        for signal in self.logger_rx.try_iter() {
+            match signal {
+                Signal::Halt => stop = Some(Stop::Exited),
+                Signal::Breakpoint => stop = stop.or(Some(Stop::Signal(SIGTRAP))),
+                // Reported by the step's outcome.
+                Signal::IllegalInstr => {}
+                Signal::Log(LogMsg::DebugPuts { value, .. }) => {
+                    // Forward the guest's output to GDB's console.
+                    let hex = encode_hex(format!("{value}\n").as_bytes());
+                    let _ = self.send_packet(&format!("O{hex}"));
+                }
+                Signal::Log(LogMsg::BreakpointExn { location, .. }) => {
+                    eprintln!("Breakpoint Exception: {location}")
+                }
+                Signal::Log(LogMsg::Error(e)) => eprintln!("!!! Error: {e}"),
+                Signal::Log(_) => {}
+            }
+        }
+        stop
+    }
+
+    /// Checks (without blocking) whether GDB has sent a `^C`.
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(self.reader.buffer().contains(&0x03));
+        }
+
+        let requested = self.reader.get_ref().peek_byte()? == Some(0x03);
+        if requested {
+            self.reader.read_exact(&mut [0u8])?;
+        }
+        Ok(requested)
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Signal(signal) => format!("S{signal:02x}"),
+        Stop::Watch(watchpoint, addr) => {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{SIGTRAP:02x}{kind}:{addr:04x};")
+        }
+        Stop::Exited => "W00".to_string(),
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Parses `ADDR,LEN` (both hex).
+fn parse_addr_len(s: &str) -> Option<(u16, u16)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u16::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::cpu::MemBlock;
+
+    /// `li $t0, 2` twice, then `halt`.
+    const ROM: &[u8] = &[0x42, 0x40, 0x00, 0x80, 0x42, 0x40, 0x00, 0x80, 0x04];
+
+    impl Incoming for &[u8] {
+        fn peek_byte(&self) -> io::Result<Option<u8>> {
+            Ok(self.first().copied())
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+    }
+
+    /// Splits the stub's output into its acks and the data of its packets,
+    /// checking each packet's checksum.
+    fn parse_output(output: &[u8]) -> (String, Vec<String>) {
+        let output = std::str::from_utf8(output).unwrap();
+        let mut acks = String::new();
+        let mut packets = Vec::new();
+        let mut rest = output;
+        while let Some(c) = rest.chars().next() {
+            if c == '$' {
+                let (data, tail) = rest[1..].split_once('#').unwrap();
+                let checksum = u8::from_str_radix(&tail[..2], 16).unwrap();
+                assert_eq!(checksum, checksum_of(data.as_bytes()), "{data}");
+                packets.push(data.to_string());
+                rest = &tail[2..];
+            } else {
+                acks.push(c);
+                rest = &rest[1..];
+            }
+        }
+        (acks, packets)
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(encode_hex(&[0x00, 0xFF, 0x7A]), "00ff7a");
+        assert_eq!(parse_addr_len("800,4"), Some((0x800, 4)));
+        assert_eq!(parse_addr_len("800"), None);
+        assert_eq!(checksum_of(b"g"), 0x67);
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+    }
+
+    #[test]
+    fn session() {
+        let input = [
+            packet("qSupported:multiprocess+"),
+            packet("?"),
+            packet("g"),
+            // Corrupted: should be NAKed and ignored.
+            "$g#00".to_string(),
+            packet("Z0,804,1"),
+            packet("c"),
+            packet("p9"),
+            packet("p10"),
+            packet("z0,804,1"),
+            packet("m800,4"),
+            packet("M1800,2:abcd"),
+            packet("m1800,2"),
+            packet("P9=0007"),
+            packet("s"),
+            packet("D"),
+        ]
+        .concat();
+
+        let (tx, rx) = mpsc::channel();
+        let rom = MemBlock::from_vec(ROM.to_vec()).unwrap();
+        let mut cpu = Cpu::headless(rom).with_observer(tx);
+        let mut output = Vec::new();
+        GdbStub::new(&mut cpu, &rx, input.as_bytes(), &mut output)
+            .run()
+            .unwrap();
+
+        let (acks, packets) = parse_output(&output);
+        assert_eq!(acks, format!("+++-{}", "+".repeat(11)));
+        assert_eq!(packets[0], "PacketSize=4000;qXfer:features:read+");
+        assert_eq!(packets[1], "S05");
+
+        // 16 GPRs, then $pc, $lo and $hi.
+        let regs = &packets[2];
+        assert_eq!(regs.len(), NUM_REGS * 4);
+        assert_eq!(&regs[..4], "0000");
+        assert_eq!(&regs[REG_PC * 4..REG_PC * 4 + 4], "0800");
+
+        assert_eq!(packets[3], "OK");
+        assert_eq!(packets[4], "S05");
+        assert_eq!(packets[5], "0002");
+        assert_eq!(packets[6], "0804");
+        assert_eq!(packets[7], "OK");
+        assert_eq!(packets[8], "42400080");
+        assert_eq!(packets[9], "OK");
+        assert_eq!(packets[10], "abcd");
+        assert_eq!(packets[11], "OK");
+        assert_eq!(packets[12], "S05");
+        assert_eq!(packets[13], "OK");
+        assert_eq!(packets.len(), 14);
+
+        assert!(cpu.breakpoints.is_empty());
+        assert_eq!(cpu.pc, 0x0808);
+        assert_eq!(cpu.regs.get::<u16>(Reg::T0), 2);
+    }
+}
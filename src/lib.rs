@@ -1,4 +1,7 @@
 pub mod cli;
 pub mod cpu;
+pub mod dap;
+pub mod gdb;
 pub mod log;
+pub mod trace_diff;
 pub mod utils;
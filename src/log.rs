@@ -8,15 +8,17 @@ macro_rules! log {
 
 #[macro_export]
 macro_rules! log_instr {
-    ([$size:expr] $name:ident) => {
+    ([$pc:expr, $size:expr] $name:ident) => {
         $crate::cpu::LogMsg::Instr {
+            pc: $pc,
             size: $size,
             name: stringify!($name).to_string(),
             args: vec![]
         }
     };
-    ([$size:expr] $name:ident $firstargval:expr $(, $argval:expr)*) => {
+    ([$pc:expr, $size:expr] $name:ident $firstargval:expr $(, $argval:expr)*) => {
         $crate::cpu::LogMsg::Instr {
+            pc: $pc,
             size: $size,
             name: stringify!($name).to_string(),
             args: vec![
@@ -1,36 +1,134 @@
-use std::{cell::RefCell, rc::Rc, sync::mpsc};
+use std::{
+    io::BufWriter,
+    sync::{mpsc, Arc, Mutex},
+};
 
 use clap::Parser;
 
 use lark_vm::{
     cli,
-    cpu::{self, interrupts::Interrupt, Cpu, LogMsg, MemBlock, MemRw, Memory, Signal},
+    cpu::{
+        self, srcmap::SourceMap, symbols::SymbolTable, timing::CostTable, trace::Tracer,
+        CmdHistory, Cpu, LogMsg, MemBlock, MemRw, Memory, Signal, StepOutcome,
+    },
+    dap, gdb, trace_diff,
 };
 
 fn main() {
     let cli = cli::Cli::parse();
 
-    let vec = std::fs::read(&cli.romfile).expect("Failed to read ROM file");
+    match &cli.command {
+        Some(cli::Command::Dap) => {
+            if let Err(e) = dap::serve(std::io::stdin(), std::io::stdout()) {
+                eprintln!("DAP server error: {e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(cli::Command::TraceDiff {
+            a,
+            b,
+            symbols,
+            context,
+        }) => {
+            let symbols = match symbols {
+                Some(path) => SymbolTable::load(path).unwrap_or_else(|err| {
+                    eprintln!("Failed to load symbols: {err}");
+                    std::process::exit(2);
+                }),
+                None => SymbolTable::new(),
+            };
+            match trace_diff::run(a, b, &symbols, *context, &mut std::io::stdout()) {
+                Ok(diverged) => std::process::exit(diverged as i32),
+                Err(err) => {
+                    eprintln!("trace-diff: {err}");
+                    std::process::exit(2);
+                }
+            }
+        }
+        None => {}
+    }
+
+    let vec = std::fs::read(cli.rom_path()).expect("Failed to read ROM file");
     let size = vec.len();
     let Some(rom) = MemBlock::from_vec(vec) else {
         eprintln!("ROM file is too large:");
         eprintln!(
             "\tFile `{}` requires {} bytes. ROM has only {} bytes.",
-            cli.romfile.display(),
+            cli.rom_path().display(),
             size,
             cpu::ROM_SIZE,
         );
         std::process::exit(1);
     };
 
-    let vtty = Rc::new(RefCell::new(MemBlock::new_zeroed()));
+    let src_map = cli.src_map_path().map(|path| {
+        SourceMap::load(&path).unwrap_or_else(|err| {
+            eprintln!("Failed to load source map: {err}");
+            std::process::exit(1);
+        })
+    });
+
+    let symbols = match cli.symbols_path() {
+        Some(path) => SymbolTable::load(&path).unwrap_or_else(|err| {
+            eprintln!("Failed to load symbols: {err}");
+            std::process::exit(1);
+        }),
+        None => SymbolTable::new(),
+    };
+
+    let cost_table = match &cli.cost_table {
+        Some(path) => CostTable::load(path).unwrap_or_else(|err| {
+            eprintln!("Failed to load cost table: {err}");
+            std::process::exit(1);
+        }),
+        None => CostTable::default(),
+    };
+
+    let vtty = Arc::new(Mutex::new(MemBlock::new_zeroed()));
     let (logger_tx, logger_rx) = mpsc::channel();
-    let (interrupt_tx, interrupt_rx) = mpsc::channel();
+    let (_interrupt_tx, interrupt_rx) = mpsc::channel();
 
     let mut cpu = Cpu::new(rom, vtty.clone(), logger_tx, interrupt_rx)
         .with_start_addr(Memory::ROM_START)
         .in_debug_mode(cli.debug)
-        .with_rom_src_path(cli.rom_src_path());
+        .with_rom_src_path(cli.rom_src_path())
+        .with_src_map(src_map)
+        .with_symbols(symbols)
+        .with_history_depth(cli.history_depth)
+        .with_cost_table(cost_table)
+        .with_profile(cli.profile)
+        .with_coverage(cli.coverage.is_some());
+
+    if let Some(path) = cli.cmd_history_path() {
+        cpu = cpu.with_cmd_history(CmdHistory::with_path(path));
+    }
+
+    if let Some(path) = &cli.trace {
+        let tracer = std::fs::File::create(path)
+            .and_then(|file| Tracer::new(Box::new(BufWriter::new(file)), cli.trace_format()));
+        match tracer {
+            Ok(tracer) => cpu = cpu.with_tracer(tracer),
+            Err(err) => {
+                eprintln!("Failed to create trace file `{}`: {err}", path.display());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = &cli.resume {
+        if let Err(err) = cpu.load_snapshot(path) {
+            eprintln!("Failed to resume from snapshot: {err}");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &cli.debug_script {
+        if let Err(err) = cpu.source_debug_script(path) {
+            eprintln!("Error in debug script: {err}");
+            std::process::exit(1);
+        }
+    }
 
     if cli.print_rom {
         for i in Memory::ROM_START..Memory::ROM_START + size as u16 {
@@ -43,15 +141,32 @@ fn main() {
         println!();
     }
 
-    loop {
-        if let Err(e) = cpu.step() {
-            cpu.log(LogMsg::Error(format!("{:?}", e)));
+    if let Some(port) = cli.gdb {
+        if let Err(e) = gdb::serve(&mut cpu, &logger_rx, port) {
+            eprintln!("GDB stub error: {e}");
+            std::process::exit(1);
         }
+    }
+
+    loop {
+        let outcome = cpu.step();
 
         for signal in logger_rx.try_iter() {
             match signal {
                 Signal::Halt => {
                     eprintln!("Exiting...");
+                    cpu.flush_trace();
+                    cpu.print_profile();
+                    if let Some(path) = &cli.coverage {
+                        if let Err(err) = cpu.write_coverage(path) {
+                            eprintln!("!!! Error: {err}");
+                        }
+                    }
+                    if let Some(path) = &cli.folded_stacks {
+                        if let Err(err) = cpu.write_folded_stacks(path) {
+                            eprintln!("!!! Error: {err}");
+                        }
+                    }
                     std::process::exit(0);
                 }
                 Signal::Log(msg) => match msg {
@@ -61,14 +176,18 @@ fn main() {
                     LogMsg::DebugPuts { addr, value } => {
                         eprintln!(">>> DebugPuts: {addr:x} '{value}'");
                     }
+                    LogMsg::BreakpointExn { pc, location } => {
+                        eprintln!("Breakpoint Exception: {location}");
+                        eprintln!("\t(at pc=0x{pc:04X})");
+                    }
                     LogMsg::MmioRead { .. } => {
                         eprintln!(">>> MMIO READ");
                     }
                     LogMsg::MmioWrite { .. } => {
                         eprintln!(">>> MMIO WRITE");
                     }
-                    LogMsg::Instr { name, args, .. } => {
-                        eprint!("{name}");
+                    LogMsg::Instr { pc, name, args, .. } => {
+                        eprint!("{}:\t{name}", cpu.symbols.fmt_addr(pc));
                         for (i, (_style, arg)) in args.iter().enumerate() {
                             if i != 0 {
                                 eprint!(", ");
@@ -83,12 +202,27 @@ fn main() {
                 Signal::Breakpoint => {
                     cpu.in_debug_mode = true;
                 }
-                Signal::IllegalInstr => {
-                    interrupt_tx
-                        .send(Interrupt::ILL_INSTR)
-                        .expect("interrupt send to closed channel!");
-                }
+                // Reported by the step's outcome.
+                Signal::IllegalInstr => {}
             }
         }
+
+        if outcome == StepOutcome::IllegalInstr {
+            eprintln!("Exiting on illegal instruction at pc=0x{:04X}...", cpu.pc);
+            cpu.flush_trace();
+            std::process::exit(1);
+        }
+
+        if let StepOutcome::Exception { code } = outcome {
+            eprintln!("Exiting on unhandled exception 0x{code:X}...");
+            cpu.flush_trace();
+            std::process::exit(1);
+        }
+
+        if let StepOutcome::KernelCall { number } = outcome {
+            eprintln!("Exiting on unhandled kernel call 0x{number:X}...");
+            cpu.flush_trace();
+            std::process::exit(1);
+        }
     }
 }
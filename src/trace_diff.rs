@@ -0,0 +1,342 @@
+//! Compares two traces written by `--trace`, to find where two runs of a
+//! program (for example, builds from different compiler versions) stop
+//! behaving the same. Traces can be in either format, which is recognized by
+//! the CSV header line.
+//!
+//! Records are compared in order. The traces diverge at the first record
+//! where the `pc`, the value of a written register, or the memory writes
+//! differ, or where one trace ends before the other. Register values are
+//! tracked from the writes seen so far, so a register written with the same
+//! value it already had in the other run doesn't count as a difference.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader, Lines, Write},
+    path::Path,
+};
+
+use crate::cpu::{
+    symbols::SymbolTable,
+    trace::{MemAccess, TraceRecord, CSV_HEADER},
+    watchpoints::Access,
+};
+
+/// Compares the traces at `a` and `b`, writing a report to `out`. Up to
+/// `context` records are shown before and after the divergence. Returns
+/// whether the traces diverge.
+pub fn run(
+    a: &Path,
+    b: &Path,
+    symbols: &SymbolTable,
+    context: usize,
+    out: &mut impl Write,
+) -> Result<bool, String> {
+    let mut a = TraceReader::open(a)?;
+    let mut b = TraceReader::open(b)?;
+    let write_err = |e: io::Error| format!("could not write report: {e}");
+
+    let mut a_regs = HashMap::new();
+    let mut b_regs = HashMap::new();
+    let mut before = VecDeque::with_capacity(context + 1);
+    let mut count = 0;
+
+    loop {
+        let (a_rec, b_rec) = (a.next_record()?, b.next_record()?);
+
+        let diffs = match (&a_rec, &b_rec) {
+            (None, None) => {
+                writeln!(out, "Traces are identical ({count} instructions).").map_err(write_err)?;
+                return Ok(false);
+            }
+            (Some(_), None) => vec![format!("`{}` ends here", b.name)],
+            (None, Some(_)) => vec![format!("`{}` ends here", a.name)],
+            (Some(a_rec), Some(b_rec)) => {
+                update_regs(&mut a_regs, a_rec);
+                update_regs(&mut b_regs, b_rec);
+                differences(a_rec, b_rec, &a_regs, &b_regs, symbols)
+            }
+        };
+
+        if diffs.is_empty() {
+            if before.len() == context {
+                before.pop_front();
+            }
+            if context > 0 {
+                before.push_back(a_rec.unwrap());
+            }
+            count += 1;
+            continue;
+        }
+
+        let report = || -> io::Result<()> {
+            writeln!(out, "Traces diverge after {count} instructions:")?;
+            for diff in &diffs {
+                writeln!(out, "  {diff}")?;
+            }
+            if !before.is_empty() {
+                writeln!(out, "\nCommon history:")?;
+                for rec in &before {
+                    writeln!(out, "    {}", fmt_record(rec, symbols))?;
+                }
+            }
+            for (reader, first) in [(&mut a, a_rec), (&mut b, b_rec)] {
+                writeln!(out, "\nThen in `{}`:", reader.name)?;
+                let Some(first) = first else {
+                    writeln!(out, "    (end of trace)")?;
+                    continue;
+                };
+                writeln!(out, "  > {}", fmt_record(&first, symbols))?;
+                for _ in 0..context {
+                    match reader.next_record() {
+                        Ok(Some(rec)) => writeln!(out, "    {}", fmt_record(&rec, symbols))?,
+                        Ok(None) => break,
+                        Err(e) => {
+                            writeln!(out, "    ({e})")?;
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        };
+        report().map_err(write_err)?;
+        return Ok(true);
+    }
+}
+
+/// Register values as far as they are known from a trace's writes.
+type RegState = HashMap<String, u16>;
+
+fn update_regs(regs: &mut RegState, rec: &TraceRecord) {
+    for (reg, value) in &rec.reg_writes {
+        regs.insert(reg.clone(), *value);
+    }
+}
+
+/// Describes how two records at the same point in their traces differ.
+fn differences(
+    a: &TraceRecord,
+    b: &TraceRecord,
+    a_regs: &RegState,
+    b_regs: &RegState,
+    symbols: &SymbolTable,
+) -> Vec<String> {
+    let mut diffs = Vec::new();
+
+    if a.pc != b.pc {
+        diffs.push(format!(
+            "pc: {} vs {}",
+            fmt_pc(a.pc, symbols),
+            fmt_pc(b.pc, symbols)
+        ));
+    }
+
+    let mut written: Vec<&String> = a.reg_writes.iter().map(|(reg, _)| reg).collect();
+    for (reg, _) in &b.reg_writes {
+        if !written.contains(&reg) {
+            written.push(reg);
+        }
+    }
+    for reg in written {
+        let (a_value, b_value) = (a_regs.get(reg), b_regs.get(reg));
+        if a_value != b_value {
+            diffs.push(format!(
+                "{reg}: {} vs {}",
+                fmt_reg_value(a_value),
+                fmt_reg_value(b_value)
+            ));
+        }
+    }
+
+    let (a_writes, b_writes) = (mem_writes(a), mem_writes(b));
+    if a_writes != b_writes {
+        diffs.push(format!("memory writes: {a_writes} vs {b_writes}"));
+    }
+
+    diffs
+}
+
+fn mem_writes(rec: &TraceRecord) -> String {
+    let writes: Vec<String> = rec
+        .mem
+        .iter()
+        .filter(|m| m.access == Access::Write)
+        .map(MemAccess::to_string)
+        .collect();
+    if writes.is_empty() {
+        "(none)".to_string()
+    } else {
+        writes.join("; ")
+    }
+}
+
+fn fmt_reg_value(value: Option<&u16>) -> String {
+    match value {
+        Some(value) => format!("0x{value:04X}"),
+        None => "(never written)".to_string(),
+    }
+}
+
+fn fmt_pc(pc: u16, symbols: &SymbolTable) -> String {
+    match symbols.describe(pc) {
+        Some(name) => format!("{name} (0x{pc:04X})"),
+        None => format!("0x{pc:04X}"),
+    }
+}
+
+fn fmt_record(rec: &TraceRecord, symbols: &SymbolTable) -> String {
+    let mut line = format!(
+        "{:>8}  {:<24} {:<6} {}",
+        rec.cycle,
+        fmt_pc(rec.pc, symbols),
+        rec.mnemonic,
+        rec.operands.join(", ")
+    );
+    for (reg, value) in &rec.reg_writes {
+        line.push_str(&format!("  {reg}=0x{value:04X}"));
+    }
+    for access in &rec.mem {
+        line.push_str(&format!("  {access}"));
+    }
+    line.trim_end().to_string()
+}
+
+struct TraceReader {
+    name: String,
+    lines: Lines<BufReader<File>>,
+    lineno: usize,
+    csv: bool,
+}
+
+impl TraceReader {
+    fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("could not open trace `{}`: {e}", path.display()))?;
+        Ok(Self {
+            name: path.display().to_string(),
+            lines: BufReader::new(file).lines(),
+            lineno: 0,
+            csv: false,
+        })
+    }
+
+    fn next_record(&mut self) -> Result<Option<TraceRecord>, String> {
+        let Some(line) = self.lines.next() else {
+            return Ok(None);
+        };
+        self.lineno += 1;
+        let err = |msg: String| format!("{}:{}: {msg}", self.name, self.lineno);
+
+        let line = line.map_err(|e| err(e.to_string()))?;
+        if self.lineno == 1 && line == CSV_HEADER {
+            self.csv = true;
+            return self.next_record();
+        }
+
+        if self.csv {
+            return TraceRecord::from_csv(&line).map(Some).map_err(err);
+        }
+        let value = serde_json::from_str(&line)
+            .map_err(|_| err("not a JSON Lines or CSV trace record".into()))?;
+        TraceRecord::from_json(&value).map(Some).map_err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn record(cycle: u64, pc: u16, reg_writes: &[(&str, u16)]) -> TraceRecord {
+        TraceRecord {
+            cycle,
+            pc,
+            ir: 0,
+            mnemonic: "li".into(),
+            operands: vec![],
+            reg_writes: reg_writes
+                .iter()
+                .map(|&(reg, value)| (reg.to_string(), value))
+                .collect(),
+            mem: vec![],
+        }
+    }
+
+    fn write_trace(name: &str, records: &[TraceRecord]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lark-{}-{name}", std::process::id()));
+        let text: String = records
+            .iter()
+            .map(|rec| format!("{}\n", rec.to_json()))
+            .collect();
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn diff(a: &Path, b: &Path) -> (bool, String) {
+        let mut out = Vec::new();
+        let diverged = run(a, b, &SymbolTable::new(), 1, &mut out).unwrap();
+        (diverged, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn compare_traces() {
+        let base = [
+            record(0, 0x0800, &[("$t0", 2)]),
+            record(1, 0x0804, &[("$t1", 1)]),
+            record(2, 0x0808, &[]),
+        ];
+        let a = write_trace("a.jsonl", &base);
+        let same = write_trace("same.jsonl", &base);
+
+        let mut pc = base.clone();
+        pc[2].pc = 0x080C;
+        let pc = write_trace("pc.jsonl", &pc);
+
+        let mut reg = base.clone();
+        reg[1].reg_writes = vec![("$t1".into(), 3)];
+        let reg = write_trace("reg.jsonl", &reg);
+
+        // The same records in CSV.
+        let csv = std::env::temp_dir().join(format!("lark-{}-same.csv", std::process::id()));
+        std::fs::write(
+            &csv,
+            format!(
+                "{CSV_HEADER}\n\
+                 0,0x0800,0x00000000,li,,$t0=0x0002,\n\
+                 1,0x0804,0x00000000,li,,$t1=0x0001,\n\
+                 2,0x0808,0x00000000,li,,,\n"
+            ),
+        )
+        .unwrap();
+
+        let results = [
+            diff(&a, &same),
+            diff(&a, &pc),
+            diff(&a, &reg),
+            diff(&a, &csv),
+        ];
+        for path in [a, same, pc, reg, csv] {
+            std::fs::remove_file(path).unwrap();
+        }
+        let [same, pc, reg, csv] = results;
+
+        assert_eq!(
+            same,
+            (false, "Traces are identical (3 instructions).\n".into())
+        );
+        assert_eq!(csv, same);
+
+        assert!(pc.0);
+        assert!(pc
+            .1
+            .starts_with("Traces diverge after 2 instructions:\n  pc: 0x0808 vs 0x080C\n"));
+        assert!(pc.1.contains("\nCommon history:\n           1  0x0804"));
+
+        assert!(reg.0);
+        assert!(reg
+            .1
+            .starts_with("Traces diverge after 1 instructions:\n  $t1: 0x0001 vs 0x0003\n"));
+    }
+}